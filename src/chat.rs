@@ -1,8 +1,63 @@
-use std::{future::Future, iter, sync::Arc};
+use std::{error::Error, fmt, future::Future, iter, sync::Arc};
 
 use anyhow::Result;
 use either::Either;
 use reqwest::Client;
+use serde_json::Value;
+
+use crate::config::ResponseFormatConfig;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ToolDefinition {
+  pub name: String,
+  pub description: String,
+  pub parameters: Value,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ToolCall {
+  pub id: String,
+  pub name: String,
+  pub arguments: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ChatMessage {
+  Message {
+    role: String,
+    content: String,
+  },
+  /// An assistant message asking for tool calls, with the text the model answered along with them.
+  ToolCalls {
+    content: Option<String>,
+    tool_calls: Vec<ToolCall>,
+  },
+  ToolResult {
+    id: String,
+    content: String,
+  },
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ChatReply {
+  pub content: Option<String>,
+  pub tool_calls: Vec<ToolCall>,
+}
+
+/// An error that interrupted a streamed answer, with the part of the answer received before it.
+#[derive(Debug)]
+pub struct PartialAnswer {
+  pub answer: String,
+  pub error: anyhow::Error,
+}
+
+impl fmt::Display for PartialAnswer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.error)
+  }
+}
+
+impl Error for PartialAnswer {}
 
 pub trait Chat {
   fn chat(
@@ -10,6 +65,32 @@ pub trait Chat {
     client: Arc<Client>,
     messages: Vec<(String, String)>,
   ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send;
+
+  /// Like `chat`, but asks the model to answer in the JSON format given by `response_format`.
+  fn chat_with_format(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    response_format: ResponseFormatConfig,
+  ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send;
+
+  /// Like `chat`, but streams the first choice, calling `on_delta` with each received part, and returns the whole
+  /// answer, or `None` without a model. Dropping the future aborts the request. An error after a part of the answer
+  /// was received is a `PartialAnswer`.
+  fn chat_stream(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    on_delta: impl FnMut(&str) + Send,
+  ) -> impl Future<Output = Result<Option<String>>> + Send;
+
+  /// Sends a conversation that may contain tool calls and their results, offering `tools` to the model.
+  fn chat_with_tools(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<ChatMessage>,
+    tools: Vec<ToolDefinition>,
+  ) -> impl Future<Output = Result<ChatReply>> + Send;
 }
 
 impl<A: Chat + Sync, B: Chat + Sync> Chat for Either<A, B> {
@@ -19,6 +100,48 @@ impl<A: Chat + Sync, B: Chat + Sync> Chat for Either<A, B> {
       Either::Right(b) => b.chat(client, messages).await.map(Either::Right),
     }
   }
+
+  async fn chat_with_format(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    response_format: ResponseFormatConfig,
+  ) -> Result<impl Iterator<Item = String>> {
+    match self {
+      Either::Left(a) => a
+        .chat_with_format(client, messages, response_format)
+        .await
+        .map(Either::Left),
+      Either::Right(b) => b
+        .chat_with_format(client, messages, response_format)
+        .await
+        .map(Either::Right),
+    }
+  }
+
+  async fn chat_stream(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    on_delta: impl FnMut(&str) + Send,
+  ) -> Result<Option<String>> {
+    match self {
+      Either::Left(a) => a.chat_stream(client, messages, on_delta).await,
+      Either::Right(b) => b.chat_stream(client, messages, on_delta).await,
+    }
+  }
+
+  async fn chat_with_tools(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<ChatMessage>,
+    tools: Vec<ToolDefinition>,
+  ) -> Result<ChatReply> {
+    match self {
+      Either::Left(a) => a.chat_with_tools(client, messages, tools).await,
+      Either::Right(b) => b.chat_with_tools(client, messages, tools).await,
+    }
+  }
 }
 
 impl<C: Chat> Chat for &C {
@@ -29,6 +152,33 @@ impl<C: Chat> Chat for &C {
   ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send {
     (*self).chat(client, messages)
   }
+
+  fn chat_with_format(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    response_format: ResponseFormatConfig,
+  ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send {
+    (*self).chat_with_format(client, messages, response_format)
+  }
+
+  fn chat_stream(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    on_delta: impl FnMut(&str) + Send,
+  ) -> impl Future<Output = Result<Option<String>>> + Send {
+    (*self).chat_stream(client, messages, on_delta)
+  }
+
+  fn chat_with_tools(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<ChatMessage>,
+    tools: Vec<ToolDefinition>,
+  ) -> impl Future<Output = Result<ChatReply>> + Send {
+    (*self).chat_with_tools(client, messages, tools)
+  }
 }
 
 impl<C: Chat> Chat for Arc<C> {
@@ -39,10 +189,64 @@ impl<C: Chat> Chat for Arc<C> {
   ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send {
     self.as_ref().chat(client, messages)
   }
+
+  fn chat_with_format(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    response_format: ResponseFormatConfig,
+  ) -> impl Future<Output = Result<impl Iterator<Item = String>>> + Send {
+    self.as_ref().chat_with_format(client, messages, response_format)
+  }
+
+  fn chat_stream(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    on_delta: impl FnMut(&str) + Send,
+  ) -> impl Future<Output = Result<Option<String>>> + Send {
+    self.as_ref().chat_stream(client, messages, on_delta)
+  }
+
+  fn chat_with_tools(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<ChatMessage>,
+    tools: Vec<ToolDefinition>,
+  ) -> impl Future<Output = Result<ChatReply>> + Send {
+    self.as_ref().chat_with_tools(client, messages, tools)
+  }
 }
 
 impl Chat for () {
   async fn chat(&self, _client: Arc<Client>, _messages: Vec<(String, String)>) -> Result<impl Iterator<Item = String>> {
     Ok(iter::empty())
   }
+
+  async fn chat_with_format(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<(String, String)>,
+    _response_format: ResponseFormatConfig,
+  ) -> Result<impl Iterator<Item = String>> {
+    Ok(iter::empty())
+  }
+
+  async fn chat_stream(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<(String, String)>,
+    _on_delta: impl FnMut(&str) + Send,
+  ) -> Result<Option<String>> {
+    Ok(None)
+  }
+
+  async fn chat_with_tools(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<ChatMessage>,
+    _tools: Vec<ToolDefinition>,
+  ) -> Result<ChatReply> {
+    Ok(ChatReply::default())
+  }
 }
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use lsp_server::{RequestId, Response as LspResponse};
+use lsp_types::{ProgressToken, TextDocumentPositionParams, Uri};
+use ramhorns::Content;
+use serde_json::Value;
+
+use crate::{document::position_to_char, render_messages, uri_to_path, State};
+
+#[derive(Content)]
+struct CommitMessageContent {
+  diff: String,
+}
+
+async fn staged_diff(directory: Option<PathBuf>) -> Result<String> {
+  let mut command = tokio::process::Command::new("git");
+  command.args(["diff", "--cached"]);
+  if let Some(directory) = directory {
+    command.current_dir(directory);
+  }
+  let output = command.output().await?;
+  if !output.status.success() {
+    return Err(anyhow!(
+      "git diff failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim()
+    ));
+  }
+  Ok(String::from_utf8(output.stdout)?)
+}
+
+impl State {
+  pub fn commit_message(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let (position, diff) = match TryInto::<[_; 2]>::try_into(arguments) {
+      Ok([position, diff]) => (position, Some(serde_json::from_value::<String>(diff)?)),
+      Err(arguments) => match TryInto::<[_; 1]>::try_into(arguments) {
+        Ok([position]) => (position, None),
+        Err(arguments) => return Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
+      },
+    };
+    let position: TextDocumentPositionParams = serde_json::from_value(position)?;
+    let uri = position.text_document.uri;
+    let document = self
+      .documents
+      .get(&uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+    let index = position_to_char(&document.rope, position.position);
+    let version = document.version;
+    drop(document);
+    let directory = self.workspace_folder(&uri);
+    let chat = self.config.commit_message.model_config.get_chat(&self.scripts);
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let future = async move {
+      let messages = match diff {
+        Some(diff) => Ok(diff),
+        None => staged_diff(directory).await,
+      }
+      .and_then(|diff| {
+        if diff.trim().is_empty() {
+          return Err(anyhow!("No staged changes"));
+        }
+        let content = CommitMessageContent { diff };
+        Ok(render_messages(&state.config.commit_message.messages, &content))
+      });
+      let choice = match messages {
+        Ok(messages) => {
+          state
+            .run_chat(&request_id_c, work_done_token, "Commit message", chat, messages, false)
+            .await
+        }
+        Err(error) => Err(error),
+      };
+      match choice {
+        Ok(choice) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+          if let Some(choice) = choice {
+            let text = state.config.commit_message.extract.extract(choice);
+            state
+              .insert_text(uri, version, index, |_| text.trim().to_string())
+              .await?;
+          }
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, future);
+    Ok(())
+  }
+
+  /// The workspace folder containing the document, or its own directory when it's outside of all of them.
+  fn workspace_folder(&self, uri: &Uri) -> Option<PathBuf> {
+    let path = uri_to_path(uri)?;
+    self
+      .workspace_folders
+      .iter()
+      .find(|folder| path.starts_with(folder))
+      .cloned()
+      .or_else(|| path.parent().map(Path::to_path_buf))
+  }
+}
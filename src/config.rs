@@ -1,10 +1,21 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+  collections::HashMap,
+  fmt::Debug,
+  path::{Component, Path, PathBuf},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
 
 use either::Either;
+use globset::{Glob, GlobMatcher};
 use ramhorns::Template;
+use regex::Regex;
 use serde::{de::Error, Deserialize};
+use serde_json::Value;
 
-use crate::{chat::Chat, infill::Infill};
+use crate::{chat::Chat, infill::Infill, mock::Scripts};
 
 pub trait Provider {
   type Model: for<'a> Deserialize<'a> + Clone + PartialEq + Debug;
@@ -126,6 +137,46 @@ impl<'a> Deserialize<'a> for TemplateConfig {
   }
 }
 
+#[derive(Clone, Debug)]
+pub struct GlobConfig(pub GlobMatcher);
+
+impl PartialEq for GlobConfig {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.glob() == other.0.glob()
+  }
+}
+
+impl<'a> Deserialize<'a> for GlobConfig {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'a>,
+  {
+    let s = String::deserialize(deserializer)?;
+    let glob = Glob::new(&s).map_err(|e| Error::custom(e.to_string()))?;
+    Ok(GlobConfig(glob.compile_matcher()))
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct RegexConfig(pub Regex);
+
+impl PartialEq for RegexConfig {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.as_str() == other.0.as_str()
+  }
+}
+
+impl<'a> Deserialize<'a> for RegexConfig {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'a>,
+  {
+    let s = String::deserialize(deserializer)?;
+    let regex = Regex::new(&s).map_err(|e| Error::custom(e.to_string()))?;
+    Ok(RegexConfig(regex))
+  }
+}
+
 #[derive(Clone, PartialEq, Debug, Deserialize, Default)]
 #[serde(tag = "provider")]
 pub enum CompletionConfig {
@@ -144,6 +195,12 @@ pub enum CompletionConfig {
     config: Arc<ModelConfig<OpenAI>>,
     template: Arc<TemplateConfig>,
   },
+  Mock {
+    config: Arc<MockConfig>,
+  },
+  Replay {
+    config: Arc<ReplayConfig>,
+  },
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Default)]
@@ -152,18 +209,334 @@ pub enum ChatModelConfig {
   #[default]
   Empty,
   OpenAI(Arc<ModelConfig<OpenAI>>),
+  Mock(Arc<MockConfig>),
+  Replay(Arc<ReplayConfig>),
+}
+
+/// Identifies a scripted provider, so that it keeps its position in the answers across requests.
+fn script_id() -> usize {
+  static NEXT: AtomicUsize = AtomicUsize::new(0);
+  NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Scripted answers, returned in order. The last one is repeated.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct MockConfig {
+  #[serde(skip_deserializing, default = "script_id")]
+  pub id: usize,
+  pub responses: Vec<MockResponse>,
+}
+
+/// A scripted answer: its text, or an assistant message asking for tool calls.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MockResponse {
+  Text(String),
+  ToolCalls {
+    #[serde(default)]
+    content: Option<String>,
+    tool_calls: Vec<MockToolCall>,
+  },
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct MockToolCall {
+  pub name: String,
+  #[serde(default)]
+  pub arguments: Value,
+}
+
+/// Answers recorded in a trace file, returned in order.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct ReplayConfig {
+  #[serde(skip_deserializing, default = "script_id")]
+  pub id: usize,
+  pub path: PathBuf,
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 pub struct MessageConfig {
   pub role: String,
-  pub content: String,
+  pub content: Arc<TemplateConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(tag = "type")]
+pub enum ExtractConfig {
+  #[default]
+  None,
+  StripFences,
+  FirstCodeBlock,
+  Regex {
+    pattern: RegexConfig,
+  },
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct PresetConfig {
+  pub title: String,
+  pub messages: Vec<MessageConfig>,
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Default)]
 pub struct RewriteConfig {
   pub model_config: ChatModelConfig,
   pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub presets: Vec<PresetConfig>,
+  #[serde(default)]
+  pub prompts: Vec<String>,
+  #[serde(default)]
+  pub preview: bool,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+  #[serde(default)]
+  pub tools: bool,
+  #[serde(default)]
+  pub stream: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct RewriteFilesConfig {
+  pub model_config: ChatModelConfig,
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub tools: bool,
+  #[serde(default)]
+  pub response_format: Option<ResponseFormatConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct GenerateConfig {
+  pub model_config: ChatModelConfig,
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct GenerateTestsConfig {
+  #[serde(default)]
+  pub model_config: ChatModelConfig,
+  #[serde(default)]
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+  #[serde(default = "GenerateTestsConfig::default_path")]
+  pub path: String,
+}
+
+impl GenerateTestsConfig {
+  fn default_path() -> String {
+    "{stem}_test.{ext}".to_string()
+  }
+
+  /// Resolves `path` relative to the directory of `source`, replacing `{name}`, `{stem}` and `{ext}` with the parts of
+  /// its file name. The result has no `.` and `..` components.
+  pub fn test_path(&self, source: &Path) -> Option<PathBuf> {
+    let name = source.file_name()?.to_str()?;
+    let stem = source.file_stem()?.to_str()?;
+    let ext = source.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let path = self
+      .path
+      .replace("{name}", name)
+      .replace("{stem}", stem)
+      .replace("{ext}", ext);
+    let mut resolved = PathBuf::new();
+    for component in source.parent()?.join(path).components() {
+      match component {
+        Component::CurDir => {}
+        Component::ParentDir => {
+          resolved.pop();
+        }
+        component => resolved.push(component),
+      }
+    }
+    Some(resolved)
+  }
+}
+
+impl Default for GenerateTestsConfig {
+  fn default() -> Self {
+    GenerateTestsConfig {
+      model_config: ChatModelConfig::Empty,
+      messages: Vec::new(),
+      extract: ExtractConfig::None,
+      path: GenerateTestsConfig::default_path(),
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct CommitMessageConfig {
+  pub model_config: ChatModelConfig,
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct DocumentConfig {
+  pub model_config: ChatModelConfig,
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Default)]
+pub enum ExplainOutput {
+  #[default]
+  Message,
+  Document,
+  None,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct ExplainConfig {
+  pub model_config: ChatModelConfig,
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub output: ExplainOutput,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct SessionConfig {
+  #[serde(default)]
+  pub model_config: ChatModelConfig,
+  #[serde(default)]
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub output: ExplainOutput,
+  #[serde(default = "SessionConfig::default_history_limit")]
+  pub history_limit: usize,
+  #[serde(default = "SessionConfig::default_session_limit")]
+  pub session_limit: usize,
+}
+
+impl SessionConfig {
+  fn default_history_limit() -> usize {
+    10
+  }
+
+  fn default_session_limit() -> usize {
+    16
+  }
+}
+
+impl Default for SessionConfig {
+  fn default() -> Self {
+    SessionConfig {
+      model_config: ChatModelConfig::Empty,
+      messages: Vec::new(),
+      output: ExplainOutput::default(),
+      history_limit: SessionConfig::default_history_limit(),
+      session_limit: SessionConfig::default_session_limit(),
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct HoverConfig {
+  #[serde(default)]
+  pub enabled: bool,
+  #[serde(default)]
+  pub model_config: ChatModelConfig,
+  #[serde(default)]
+  pub messages: Vec<MessageConfig>,
+  #[serde(default = "HoverConfig::default_context_lines")]
+  pub context_lines: usize,
+  #[serde(default = "HoverConfig::default_min_interval_ms")]
+  pub min_interval_ms: u64,
+}
+
+impl HoverConfig {
+  fn default_context_lines() -> usize {
+    20
+  }
+
+  fn default_min_interval_ms() -> u64 {
+    1000
+  }
+}
+
+impl Default for HoverConfig {
+  fn default() -> Self {
+    HoverConfig {
+      enabled: false,
+      model_config: ChatModelConfig::Empty,
+      messages: Vec::new(),
+      context_lines: HoverConfig::default_context_lines(),
+      min_interval_ms: HoverConfig::default_min_interval_ms(),
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum ResponseFormatConfig {
+  JsonObject,
+  JsonSchema {
+    name: String,
+    schema: Value,
+    #[serde(default)]
+    strict: bool,
+  },
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct ReviewConfig {
+  #[serde(default)]
+  pub enabled: bool,
+  #[serde(default)]
+  pub model_config: ChatModelConfig,
+  #[serde(default)]
+  pub messages: Vec<MessageConfig>,
+  #[serde(default)]
+  pub extract: ExtractConfig,
+  #[serde(default)]
+  pub response_format: Option<ResponseFormatConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct TraceConfig {
+  /// JSONL file to append requests and responses to. Tracing is disabled without it.
+  #[serde(default)]
+  pub path: Option<PathBuf>,
+  #[serde(default)]
+  pub redact_content: bool,
+}
+
+/// Price in US dollars per million tokens.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct PriceConfig {
+  pub prompt: f64,
+  pub completion: f64,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Default)]
+pub struct UsageConfig {
+  /// Prices by model name.
+  #[serde(default)]
+  pub prices: HashMap<String, PriceConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct OverrideConfig {
+  #[serde(default)]
+  pub language_ids: Vec<String>,
+  #[serde(default)]
+  pub patterns: Vec<GlobConfig>,
+  #[serde(default)]
+  pub infill: Option<CompletionConfig>,
+  #[serde(default)]
+  pub rewrite: Option<RewriteConfig>,
+}
+
+impl OverrideConfig {
+  pub fn matches(&self, language_id: &str, path: &str) -> bool {
+    (self.language_ids.is_empty() || self.language_ids.iter().any(|id| id == language_id))
+      && (self.patterns.is_empty() || self.patterns.iter().any(|pattern| pattern.0.is_match(path)))
+  }
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize)]
@@ -172,37 +545,94 @@ pub struct Config {
   pub infill: CompletionConfig,
   #[serde(default)]
   pub rewrite: RewriteConfig,
+  #[serde(default)]
+  pub rewrite_files: RewriteFilesConfig,
+  #[serde(default)]
+  pub generate: GenerateConfig,
+  #[serde(default)]
+  pub commit_message: CommitMessageConfig,
+  #[serde(default)]
+  pub document: DocumentConfig,
+  #[serde(default)]
+  pub generate_tests: GenerateTestsConfig,
+  #[serde(default)]
+  pub explain: ExplainConfig,
+  #[serde(default)]
+  pub session: SessionConfig,
+  #[serde(default)]
+  pub hover: HoverConfig,
+  #[serde(default)]
+  pub review: ReviewConfig,
+  #[serde(default)]
+  pub usage: UsageConfig,
+  #[serde(default)]
+  pub trace: TraceConfig,
+  #[serde(default)]
+  pub overrides: Vec<OverrideConfig>,
 }
 
 impl Config {
-  pub fn get_infill(&self) -> impl Infill + Clone + Send {
-    match self.infill {
-      CompletionConfig::Empty => Either::Left(Either::Left(())),
-      CompletionConfig::Mistral { ref config } => Either::Left(Either::Right(config.clone())),
-      CompletionConfig::LlamaCpp { ref config } => Either::Right(Either::Left(config.clone())),
-      CompletionConfig::Ollama { ref config } => Either::Right(Either::Right(Either::Left(config.clone()))),
-      CompletionConfig::OpenAICompletions {
-        ref config,
-        ref template,
-      } => Either::Right(Either::Right(Either::Right((template.clone(), config.clone())))),
+  pub fn get_infill_config(&self, language_id: &str, path: &str) -> &CompletionConfig {
+    self
+      .overrides
+      .iter()
+      .filter(|o| o.matches(language_id, path))
+      .find_map(|o| o.infill.as_ref())
+      .unwrap_or(&self.infill)
+  }
+
+  pub fn get_rewrite_config(&self, language_id: &str, path: &str) -> &RewriteConfig {
+    self
+      .overrides
+      .iter()
+      .filter(|o| o.matches(language_id, path))
+      .find_map(|o| o.rewrite.as_ref())
+      .unwrap_or(&self.rewrite)
+  }
+}
+
+impl CompletionConfig {
+  pub fn get_infill(&self, scripts: &Scripts) -> impl Infill + Clone + Send {
+    match self {
+      CompletionConfig::Empty => Either::Left(Either::Left(Either::Left(()))),
+      CompletionConfig::Mistral { config } => Either::Left(Either::Left(Either::Right(config.clone()))),
+      CompletionConfig::LlamaCpp { config } => Either::Left(Either::Right(Either::Left(config.clone()))),
+      CompletionConfig::Ollama { config } => Either::Left(Either::Right(Either::Right(config.clone()))),
+      CompletionConfig::OpenAICompletions { config, template } => {
+        Either::Right(Either::Left((template.clone(), config.clone())))
+      }
+      CompletionConfig::Mock { config } => Either::Right(Either::Right(scripts.mock(config))),
+      CompletionConfig::Replay { config } => Either::Right(Either::Right(scripts.replay(config))),
     }
   }
+}
 
-  pub fn get_rewrite(&self) -> impl Chat + Clone + Send {
-    match self.rewrite.model_config {
+impl ChatModelConfig {
+  pub fn get_chat(&self, scripts: &Scripts) -> impl Chat + Clone + Send {
+    match self {
       ChatModelConfig::Empty => Either::Left(()),
-      ChatModelConfig::OpenAI(ref config) => Either::Right(config.clone()),
+      ChatModelConfig::OpenAI(config) => Either::Right(Either::Left(config.clone())),
+      ChatModelConfig::Mock(config) => Either::Right(Either::Right(scripts.mock(config))),
+      ChatModelConfig::Replay(config) => Either::Right(Either::Right(scripts.replay(config))),
     }
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use std::sync::Arc;
+  use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+  };
 
   use ramhorns::Template;
 
-  use crate::config::{CompletionConfig, Config, GenerationConfig, ModelConfig, RewriteConfig};
+  use crate::config::{
+    CommitMessageConfig, CompletionConfig, Config, DocumentConfig, ExplainConfig, GenerateConfig, GenerateTestsConfig,
+    GenerationConfig, HoverConfig, MockResponse, ModelConfig, PriceConfig, ReviewConfig, RewriteConfig,
+    RewriteFilesConfig, SessionConfig, TraceConfig, UsageConfig,
+  };
 
   #[test]
   fn mistral_infill_config() {
@@ -241,6 +671,18 @@ mod tests {
         }),
       },
       rewrite: RewriteConfig::default(),
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig::default(),
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
     };
     let parsed: Config = serde_json::from_str(str).unwrap();
     assert_eq!(parsed, config);
@@ -280,6 +722,18 @@ mod tests {
         }),
       },
       rewrite: RewriteConfig::default(),
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig::default(),
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
     };
     let parsed: Config = serde_json::from_str(str).unwrap();
     assert_eq!(parsed, config);
@@ -320,6 +774,18 @@ mod tests {
         }),
       },
       rewrite: RewriteConfig::default(),
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig::default(),
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
     };
     let parsed: Config = serde_json::from_str(str).unwrap();
     assert_eq!(parsed, config);
@@ -364,6 +830,18 @@ mod tests {
         )),
       },
       rewrite: RewriteConfig::default(),
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig::default(),
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
     };
     let parsed: Config = serde_json::from_str(str).unwrap();
     assert_eq!(parsed, config);
@@ -417,15 +895,547 @@ mod tests {
         })),
         messages: vec![super::MessageConfig {
           role: "system".to_string(),
-          content: "You provide the modified code directly without any surrounding explanation or context, and do not enclose it within a code block.".to_string(),
+          content: Arc::new(super::TemplateConfig(Template::new("You provide the modified code directly without any surrounding explanation or context, and do not enclose it within a code block.").unwrap())),
         },
         super::MessageConfig {
           role: "user".to_string(),
-          content: "{{ prompt }}\n\n```\n{{ selection }}\n```".to_string(),
+          content: Arc::new(super::TemplateConfig(Template::new("{{ prompt }}\n\n```\n{{ selection }}\n```").unwrap())),
+        }],
+        presets: Vec::new(),
+        prompts: Vec::new(),
+        preview: false,
+        extract: super::ExtractConfig::None,
+        tools: false,
+        stream: false,
+      },
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig::default(),
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
+    };
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(parsed, config);
+  }
+
+  #[test]
+  fn overrides_config() {
+    let str = r#"
+    {
+      "infill": {
+        "provider": "LlamaCpp",
+        "config": {
+          "url": "http://localhost:8080/infill"
+        }
+      },
+      "overrides": [
+        {
+          "language_ids": ["python", "c"],
+          "infill": {
+            "provider": "LlamaCpp",
+            "config": {
+              "url": "http://localhost:8080/infill",
+              "stop": ["\n\n"]
+            }
+          }
+        },
+        {
+          "patterns": ["**/*.sql"],
+          "infill": {
+            "provider": "Ollama",
+            "config": {
+              "url": "http://localhost:11434/api/generate",
+              "model": "qwen2.5-coder"
+            }
+          }
+        }
+      ]
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(parsed.get_infill_config("rust", "/src/main.rs"), &parsed.infill);
+    assert_eq!(
+      parsed.get_infill_config("python", "/src/main.py"),
+      parsed.overrides[0].infill.as_ref().unwrap()
+    );
+    assert_eq!(
+      parsed.get_infill_config("sql", "/db/schema.sql"),
+      parsed.overrides[1].infill.as_ref().unwrap()
+    );
+    assert_eq!(parsed.get_rewrite_config("python", "/src/main.py"), &parsed.rewrite);
+  }
+
+  #[test]
+  fn rewrite_presets_config() {
+    let str = r#"
+    {
+      "rewrite": {
+        "model_config": {
+          "provider": "OpenAI",
+          "config": {
+            "url": "http://localhost:8080/v1/chat/completions"
+          }
+        },
+        "messages": [],
+        "presets": [
+          {
+            "title": "Add docs",
+            "messages": [
+              {
+                "role": "user",
+                "content": "Add documentation comments to the following code.\n\n```\n{{ selection }}\n```"
+              }
+            ]
+          }
+        ],
+        "prompts": ["Fix bug", "Simplify"]
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.rewrite.prompts,
+      vec!["Fix bug".to_string(), "Simplify".to_string()]
+    );
+    assert_eq!(
+      parsed.rewrite.presets,
+      vec![super::PresetConfig {
+        title: "Add docs".to_string(),
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(
+            Template::new("Add documentation comments to the following code.\n\n```\n{{ selection }}\n```").unwrap()
+          )),
+        }],
+      }]
+    );
+  }
+
+  #[test]
+  fn rewrite_extract_config() {
+    let str = r#"
+    {
+      "rewrite": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [],
+        "extract": {
+          "type": "Regex",
+          "pattern": "(?s)```\\w*\\n(.*?)```"
+        },
+        "stream": true
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.rewrite.extract,
+      super::ExtractConfig::Regex {
+        pattern: super::RegexConfig(regex::Regex::new(r"(?s)```\w*\n(.*?)```").unwrap()),
+      }
+    );
+    assert!(parsed.rewrite.stream);
+  }
+
+  #[test]
+  fn rewrite_files_config() {
+    let str = r#"
+    {
+      "rewrite_files": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [
+          {
+            "role": "user",
+            "content": "{{ prompt }}{{#files}}\n\n```{{ tag }}\n{{ selection }}```{{/files}}"
+          }
+        ],
+        "tools": true
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.rewrite_files,
+      RewriteFilesConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(
+            Template::new("{{ prompt }}{{#files}}\n\n```{{ tag }}\n{{ selection }}```{{/files}}").unwrap(),
+          )),
+        }],
+        tools: true,
+        response_format: None,
+      }
+    );
+  }
+
+  #[test]
+  fn generate_config() {
+    let str = r#"
+    {
+      "generate": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [
+          {
+            "role": "user",
+            "content": "{{ prompt }}\n\n```\n{{ prefix }}<CURSOR>{{ suffix }}\n```"
+          }
+        ],
+        "extract": {
+          "type": "FirstCodeBlock"
+        }
+      }
+    }
+    "#;
+    let config = Config {
+      infill: CompletionConfig::default(),
+      rewrite: RewriteConfig::default(),
+      rewrite_files: RewriteFilesConfig::default(),
+      generate: GenerateConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(
+            Template::new("{{ prompt }}\n\n```\n{{ prefix }}<CURSOR>{{ suffix }}\n```").unwrap(),
+          )),
         }],
+        extract: super::ExtractConfig::FirstCodeBlock,
       },
+      commit_message: CommitMessageConfig::default(),
+      document: DocumentConfig::default(),
+      generate_tests: GenerateTestsConfig::default(),
+      explain: ExplainConfig::default(),
+      session: SessionConfig::default(),
+      hover: HoverConfig::default(),
+      review: ReviewConfig::default(),
+      usage: UsageConfig::default(),
+      trace: TraceConfig::default(),
+      overrides: Vec::new(),
     };
     let parsed: Config = serde_json::from_str(str).unwrap();
     assert_eq!(parsed, config);
   }
+  #[test]
+  fn commit_message_config() {
+    let str = r#"
+    {
+      "commit_message": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [
+          {
+            "role": "user",
+            "content": "Write a commit message for:\n\n{{ diff }}"
+          }
+        ]
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.commit_message,
+      CommitMessageConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(
+            Template::new("Write a commit message for:\n\n{{ diff }}").unwrap(),
+          )),
+        }],
+        extract: super::ExtractConfig::None,
+      }
+    );
+  }
+  #[test]
+  fn document_config() {
+    let str = r#"
+    {
+      "document": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [],
+        "extract": {
+          "type": "StripFences"
+        }
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.document,
+      DocumentConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: Vec::new(),
+        extract: super::ExtractConfig::StripFences,
+      }
+    );
+  }
+  #[test]
+  fn generate_tests_config() {
+    let str = r#"
+    {
+      "generate_tests": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [],
+        "path": "../tests/{stem}_test.{ext}"
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.generate_tests,
+      GenerateTestsConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: Vec::new(),
+        extract: super::ExtractConfig::None,
+        path: "../tests/{stem}_test.{ext}".to_string(),
+      }
+    );
+    assert_eq!(
+      parsed.generate_tests.test_path(Path::new("/project/src/foo.rs")),
+      Some(PathBuf::from("/project/tests/foo_test.rs"))
+    );
+    assert_eq!(
+      GenerateTestsConfig::default().test_path(Path::new("/project/foo.py")),
+      Some(PathBuf::from("/project/foo_test.py"))
+    );
+  }
+
+  #[test]
+  fn explain_config() {
+    let str = r#"
+    {
+      "explain": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [
+          {
+            "role": "user",
+            "content": "Explain the following code.\n\n```\n{{ selection }}\n```"
+          }
+        ],
+        "output": "Document"
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.explain,
+      ExplainConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(
+            Template::new("Explain the following code.\n\n```\n{{ selection }}\n```").unwrap(),
+          )),
+        }],
+        output: super::ExplainOutput::Document,
+      }
+    );
+  }
+
+  #[test]
+  fn session_config() {
+    let str = r#"
+    {
+      "session": {
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [
+          {
+            "role": "user",
+            "content": "{{ prompt }}"
+          }
+        ],
+        "output": "None",
+        "history_limit": 4,
+        "session_limit": 2
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.session,
+      SessionConfig {
+        model_config: super::ChatModelConfig::Empty,
+        messages: vec![super::MessageConfig {
+          role: "user".to_string(),
+          content: Arc::new(super::TemplateConfig(Template::new("{{ prompt }}").unwrap())),
+        }],
+        output: super::ExplainOutput::None,
+        history_limit: 4,
+        session_limit: 2,
+      }
+    );
+    let parsed: Config = serde_json::from_str("{}").unwrap();
+    assert_eq!(parsed.session.history_limit, 10);
+    assert_eq!(parsed.session.session_limit, 16);
+  }
+
+  #[test]
+  fn hover_config() {
+    let str = r#"
+    {
+      "hover": {
+        "enabled": true,
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [],
+        "min_interval_ms": 500
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.hover,
+      HoverConfig {
+        enabled: true,
+        model_config: super::ChatModelConfig::Empty,
+        messages: Vec::new(),
+        context_lines: 20,
+        min_interval_ms: 500,
+      }
+    );
+  }
+
+  #[test]
+  fn review_config() {
+    let str = r#"
+    {
+      "review": {
+        "enabled": true,
+        "model_config": {
+          "provider": "Empty"
+        },
+        "messages": [],
+        "extract": { "type": "StripFences" },
+        "response_format": {
+          "type": "JsonSchema",
+          "name": "review",
+          "schema": { "type": "object" }
+        }
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.review,
+      ReviewConfig {
+        enabled: true,
+        model_config: super::ChatModelConfig::Empty,
+        messages: Vec::new(),
+        extract: super::ExtractConfig::StripFences,
+        response_format: Some(super::ResponseFormatConfig::JsonSchema {
+          name: "review".to_string(),
+          schema: serde_json::json!({ "type": "object" }),
+          strict: false,
+        }),
+      }
+    );
+    let parsed: Config = serde_json::from_str("{}").unwrap();
+    assert!(!parsed.review.enabled);
+  }
+
+  #[test]
+  fn trace_config() {
+    let str = r#"
+    {
+      "trace": {
+        "path": "/tmp/famulus.jsonl",
+        "redact_content": true
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.trace,
+      TraceConfig {
+        path: Some(PathBuf::from("/tmp/famulus.jsonl")),
+        redact_content: true,
+      }
+    );
+  }
+
+  #[test]
+  fn mock_config() {
+    let str = r#"
+    {
+      "infill": {
+        "provider": "Mock",
+        "config": {
+          "responses": ["a", "b"]
+        }
+      },
+      "rewrite": {
+        "model_config": {
+          "provider": "Replay",
+          "config": {
+            "path": "/tmp/famulus.jsonl"
+          }
+        },
+        "messages": []
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    let CompletionConfig::Mock { config: mock } = parsed.infill else {
+      panic!("Expected a Mock provider");
+    };
+    assert_eq!(
+      mock.responses,
+      vec![MockResponse::Text("a".to_string()), MockResponse::Text("b".to_string())]
+    );
+    let super::ChatModelConfig::Replay(replay) = parsed.rewrite.model_config else {
+      panic!("Expected a Replay provider");
+    };
+    assert_eq!(replay.path, PathBuf::from("/tmp/famulus.jsonl"));
+    // Each configured provider gets its own script.
+    assert_ne!(mock.id, replay.id);
+  }
+
+  #[test]
+  fn usage_config() {
+    let str = r#"
+    {
+      "usage": {
+        "prices": {
+          "gpt-4o-mini": { "prompt": 0.15, "completion": 0.6 }
+        }
+      }
+    }
+    "#;
+    let parsed: Config = serde_json::from_str(str).unwrap();
+    assert_eq!(
+      parsed.usage,
+      UsageConfig {
+        prices: HashMap::from([(
+          "gpt-4o-mini".to_string(),
+          PriceConfig {
+            prompt: 0.15,
+            completion: 0.6,
+          }
+        )]),
+      }
+    );
+  }
 }
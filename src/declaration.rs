@@ -0,0 +1,267 @@
+use anyhow::{anyhow, Result};
+use lsp_server::{ErrorCode, Message, RequestId, Response as LspResponse};
+use lsp_types::{ProgressToken, TextDocumentPositionParams};
+use ramhorns::Content;
+use ropey::Rope;
+use serde_json::Value;
+
+use crate::{
+  indent::{self, indentation},
+  render_messages, trace, RopeSliceContent, State,
+};
+
+const KEYWORDS: &[&str] = &[
+  "class",
+  "def",
+  "enum",
+  "fn",
+  "func",
+  "function",
+  "impl",
+  "interface",
+  "mod",
+  "module",
+  "struct",
+  "trait",
+  "type",
+  "union",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Declaration {
+  /// Line to insert a doc comment at, above any attributes or decorators.
+  pub insert_line: usize,
+  pub start_line: usize,
+  /// Exclusive.
+  pub end_line: usize,
+}
+
+fn line(rope: &Rope, index: usize) -> String {
+  rope.line(index).to_string()
+}
+
+fn is_blank(line: &str) -> bool {
+  line.trim().is_empty()
+}
+
+fn is_closing(line: &str) -> bool {
+  let trimmed = line.trim_start();
+  trimmed.starts_with(['}', ')', ']']) || trimmed.split_whitespace().next() == Some("end")
+}
+
+fn is_attribute(line: &str) -> bool {
+  let trimmed = line.trim_start();
+  trimmed.starts_with("#[") || trimmed.starts_with('@')
+}
+
+fn is_declaration(line: &str) -> bool {
+  let trimmed = line.trim_start();
+  if !trimmed.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+    return false;
+  }
+  let head = trimmed.split(['(', '{', '=', ':', '<', ';']).next().unwrap_or_default();
+  head
+    .split(|c: char| !c.is_alphanumeric() && c != '_')
+    .any(|word| KEYWORDS.contains(&word))
+}
+
+/// Finds the function or type declaration enclosing `cursor_line` by indentation, or the one starting below it when
+/// the cursor is on a blank line.
+pub fn enclosing_declaration(rope: &Rope, cursor_line: usize) -> Option<Declaration> {
+  let len_lines = rope.len_lines();
+  let cursor_line = (cursor_line..len_lines).find(|&index| !is_blank(&line(rope, index)))?;
+  let cursor_text = line(rope, cursor_line);
+  let mut limit = indentation(&cursor_text).len();
+  if is_closing(&cursor_text) {
+    limit += 1;
+  }
+
+  let start_line = if is_declaration(&cursor_text) {
+    cursor_line
+  } else {
+    let mut start_line = None;
+    for index in (0..cursor_line).rev() {
+      let text = line(rope, index);
+      if is_blank(&text) || is_closing(&text) {
+        continue;
+      }
+      let indent = indentation(&text).len();
+      if indent < limit {
+        if is_declaration(&text) {
+          start_line = Some(index);
+          break;
+        }
+        limit = indent;
+      }
+    }
+    start_line?
+  };
+
+  let start_indent = indentation(&line(rope, start_line)).len();
+  let mut end_line = start_line + 1;
+  for index in start_line + 1..len_lines {
+    let text = line(rope, index);
+    if is_blank(&text) {
+      continue;
+    }
+    let indent = indentation(&text).len();
+    if indent > start_indent || (indent == start_indent && is_closing(&text)) {
+      end_line = index + 1;
+    } else {
+      break;
+    }
+  }
+
+  let mut insert_line = start_line;
+  while insert_line > 0 {
+    let text = line(rope, insert_line - 1);
+    if indentation(&text).len() != start_indent || !is_attribute(&text) {
+      break;
+    }
+    insert_line -= 1;
+  }
+
+  Some(Declaration {
+    insert_line,
+    start_line,
+    end_line,
+  })
+}
+
+#[derive(Content)]
+struct DeclarationContent<'a> {
+  language_id: String,
+  selection: RopeSliceContent<'a>,
+  prefix: RopeSliceContent<'a>,
+  suffix: RopeSliceContent<'a>,
+}
+
+impl State {
+  pub fn document(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let [position] = TryInto::<[_; 1]>::try_into(arguments)
+      .map_err(|arguments| anyhow!("Wrong number of arguments: {}", arguments.len()))?;
+    let position: TextDocumentPositionParams = serde_json::from_value(position)?;
+    let uri = position.text_document.uri;
+    let document = self
+      .documents
+      .get(&uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+    let Some(declaration) = enclosing_declaration(&document.rope, position.position.line as usize) else {
+      self.sender.send(Message::Response(LspResponse::new_err(
+        request_id,
+        ErrorCode::InvalidParams as i32,
+        format!("No declaration found at line {}", position.position.line + 1),
+      )))?;
+      return Ok(());
+    };
+    let start_index = document.rope.line_to_char(declaration.start_line);
+    let end_index = document.rope.line_to_char(declaration.end_line);
+    let content = DeclarationContent {
+      language_id: document.language_id.clone(),
+      selection: document.rope.slice(start_index..end_index).into(),
+      prefix: document.rope.slice(..start_index).into(),
+      suffix: document.rope.slice(end_index..).into(),
+    };
+    let messages = render_messages(&self.config.document.messages, &content);
+    let index = document.rope.line_to_char(declaration.insert_line);
+    let indentation = indent::indentation(&document.rope.line(declaration.start_line).to_string()).to_string();
+    let version = document.version;
+    drop(document);
+    let chat = self.config.document.model_config.get_chat(&self.scripts);
+    let extract = self.config.document.extract.clone();
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = uri.clone();
+    let future = async move {
+      let choice = state
+        .run_chat(&request_id_c, work_done_token, "Document", chat, messages, false)
+        .await;
+      match choice {
+        Ok(choice) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+          if let Some(choice) = choice {
+            let mut comment = indent::dedent(extract.extract(choice).trim_matches('\n'));
+            comment.push('\n');
+            state
+              .insert_text(uri, version, index, |_| indent::indent(&comment, &indentation, true))
+              .await?;
+          }
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use ropey::Rope;
+
+  use super::{enclosing_declaration, Declaration};
+
+  fn declaration(insert_line: usize, start_line: usize, end_line: usize) -> Option<Declaration> {
+    Some(Declaration {
+      insert_line,
+      start_line,
+      end_line,
+    })
+  }
+
+  #[test]
+  fn rust() {
+    let rope = Rope::from_str(
+      "use std::fmt;
+
+impl Foo {
+  #[inline]
+  pub fn bar(
+    &self,
+  ) -> u32 {
+    fn inner() {}
+    let a = 1;
+
+    a + 1
+  }
+}
+",
+    );
+    assert_eq!(enclosing_declaration(&rope, 0), None);
+    assert_eq!(enclosing_declaration(&rope, 1), declaration(2, 2, 13));
+    assert_eq!(enclosing_declaration(&rope, 4), declaration(3, 4, 12));
+    assert_eq!(enclosing_declaration(&rope, 5), declaration(3, 4, 12));
+    assert_eq!(enclosing_declaration(&rope, 7), declaration(7, 7, 8));
+    assert_eq!(enclosing_declaration(&rope, 8), declaration(3, 4, 12));
+    assert_eq!(enclosing_declaration(&rope, 11), declaration(3, 4, 12));
+    assert_eq!(enclosing_declaration(&rope, 12), declaration(2, 2, 13));
+    assert_eq!(enclosing_declaration(&rope, 13), None);
+  }
+
+  #[test]
+  fn python() {
+    let rope = Rope::from_str(
+      "class Foo:
+    @property
+    def bar(self):
+        # the fn bar
+        return 1
+
+    def baz(self):
+        pass
+",
+    );
+    assert_eq!(enclosing_declaration(&rope, 3), declaration(1, 2, 5));
+    assert_eq!(enclosing_declaration(&rope, 4), declaration(1, 2, 5));
+    assert_eq!(enclosing_declaration(&rope, 5), declaration(6, 6, 8));
+    assert_eq!(enclosing_declaration(&rope, 0), declaration(0, 0, 8));
+  }
+}
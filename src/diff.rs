@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use lsp_types::{Position, Range, TextEdit};
+use ropey::Rope;
+use similar::{DiffOp, TextDiff};
+
+const CHAR_DIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn offsets(slices: &[&str]) -> (Vec<usize>, Vec<usize>) {
+  let mut bytes = vec![0];
+  let mut chars = vec![0];
+  for slice in slices {
+    bytes.push(bytes.last().unwrap() + slice.len());
+    chars.push(chars.last().unwrap() + slice.chars().count());
+  }
+  (bytes, chars)
+}
+
+fn char_to_position(rope: &Rope, start: Position, index: usize) -> Position {
+  let line = rope.char_to_line(index);
+  let character = (index - rope.line_to_char(line)) as u32;
+  if line == 0 {
+    Position::new(start.line, start.character + character)
+  } else {
+    Position::new(start.line + line as u32, character)
+  }
+}
+
+/// Computes minimal edits turning `original` into `new`, where `original` is located at `start` in the document.
+/// Lines are compared first, then changed lines are compared char by char.
+pub fn diff_edits(original: &str, new: &str, start: Position) -> Vec<TextEdit> {
+  let rope = Rope::from_str(original);
+  let lines = TextDiff::from_lines(original, new);
+  let (old_bytes, old_chars) = offsets(lines.old_slices());
+  let (new_bytes, _) = offsets(lines.new_slices());
+  let mut edits = Vec::new();
+  for op in lines.ops() {
+    if let DiffOp::Equal { .. } = op {
+      continue;
+    }
+    let old_range = op.old_range();
+    let new_range = op.new_range();
+    let old_chunk = &original[old_bytes[old_range.start]..old_bytes[old_range.end]];
+    let new_chunk = &new[new_bytes[new_range.start]..new_bytes[new_range.end]];
+    let chunk_start = old_chars[old_range.start];
+    let new_chunk_chars = new_chunk.chars().collect::<Vec<_>>();
+    let chars = TextDiff::configure()
+      .timeout(CHAR_DIFF_TIMEOUT)
+      .diff_chars(old_chunk, new_chunk);
+    for op in chars.ops() {
+      if let DiffOp::Equal { .. } = op {
+        continue;
+      }
+      let old_range = op.old_range();
+      edits.push(TextEdit {
+        range: Range::new(
+          char_to_position(&rope, start, chunk_start + old_range.start),
+          char_to_position(&rope, start, chunk_start + old_range.end),
+        ),
+        new_text: new_chunk_chars[op.new_range()].iter().collect(),
+      });
+    }
+  }
+  edits
+}
+
+#[cfg(test)]
+mod tests {
+  use lsp_types::{Position, Range, TextEdit};
+  use ropey::Rope;
+
+  use crate::document::apply_text_edits;
+
+  use super::diff_edits;
+
+  fn check(prefix: &str, original: &str, new: &str) -> Vec<TextEdit> {
+    let mut rope = Rope::from_str(&format!("{}{}", prefix, original));
+    let start_index = prefix.chars().count();
+    let line = rope.char_to_line(start_index);
+    let start = Position::new(line as u32, (start_index - rope.line_to_char(line)) as u32);
+    let edits = diff_edits(original, new, start);
+    apply_text_edits(&mut rope, &edits);
+    assert_eq!(rope.to_string(), format!("{}{}", prefix, new));
+    edits
+  }
+
+  #[test]
+  fn identical() {
+    assert_eq!(check("", "fn main() {}\n", "fn main() {}\n"), vec![]);
+  }
+
+  #[test]
+  fn untouched_lines() {
+    let edits = check(
+      "",
+      "fn main() {\n  let a = 1;\n  println!(\"{}\", a);\n}\n",
+      "fn main() {\n  let b = 1;\n  println!(\"{}\", b);\n}\n",
+    );
+    assert_eq!(
+      edits,
+      vec![
+        TextEdit {
+          range: Range::new(Position::new(1, 6), Position::new(1, 7)),
+          new_text: "b".to_string(),
+        },
+        TextEdit {
+          range: Range::new(Position::new(2, 17), Position::new(2, 18)),
+          new_text: "b".to_string(),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn inserted_and_removed_lines() {
+    check("", "a\nb\nc\n", "a\nx\ny\nc\nd\n");
+    check("", "a\nb\nc\n", "c\n");
+    check("", "a\nb", "a\nb\n");
+  }
+
+  #[test]
+  fn offset_selection() {
+    let edits = check("// header\nlet x = ", "foo(1);\nbar(2);", "foo(10);\nbar(2);");
+    assert_eq!(
+      edits,
+      vec![TextEdit {
+        range: Range::new(Position::new(1, 13), Position::new(1, 13)),
+        new_text: "0".to_string(),
+      }]
+    );
+  }
+
+  #[test]
+  fn unicode() {
+    check("é ", "ü = \"ä\";\n", "ü = \"äö\";\n");
+  }
+}
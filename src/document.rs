@@ -0,0 +1,176 @@
+use std::collections::{HashMap, VecDeque};
+
+use lsp_types::{Position, Range, TextEdit};
+use ropey::Rope;
+
+const MAX_CHANGES: usize = 1024;
+
+pub fn position_to_char(rope: &Rope, position: Position) -> usize {
+  rope.line_to_char(position.line as usize) + position.character as usize
+}
+
+pub fn char_to_position(rope: &Rope, index: usize) -> Position {
+  let line = rope.char_to_line(index);
+  Position::new(line as u32, (index - rope.line_to_char(line)) as u32)
+}
+
+pub fn apply_text_edits(rope: &mut Rope, edits: &[TextEdit]) {
+  let mut edits = edits.iter().collect::<Vec<_>>();
+  edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+  for edit in edits.into_iter().rev() {
+    let start_index = position_to_char(rope, edit.range.start);
+    let end_index = position_to_char(rope, edit.range.end);
+    rope.remove(start_index..end_index);
+    rope.insert(start_index, &edit.new_text);
+  }
+}
+
+#[derive(Debug)]
+struct Change {
+  version: i32,
+  start: usize,
+  end: usize,
+  len: usize,
+}
+
+#[derive(Debug)]
+pub struct Document {
+  pub rope: Rope,
+  pub version: i32,
+  pub language_id: String,
+  pub hovers: HashMap<(usize, usize), String>,
+  pub saved: Rope,
+  changes: VecDeque<Change>,
+  changes_version: i32,
+}
+
+impl Document {
+  pub fn new(rope: Rope, version: i32, language_id: String) -> Self {
+    Document {
+      saved: rope.clone(),
+      rope,
+      version,
+      language_id,
+      hovers: HashMap::new(),
+      changes: VecDeque::new(),
+      changes_version: version,
+    }
+  }
+
+  pub fn change(&mut self, version: i32, range: Option<Range>, text: &str) {
+    let (start, end) = match range {
+      Some(range) => (
+        position_to_char(&self.rope, range.start),
+        position_to_char(&self.rope, range.end),
+      ),
+      None => (0, self.rope.len_chars()),
+    };
+    self.rope.remove(start..end);
+    self.rope.insert(start, text);
+    self.hovers.clear();
+    if self.changes.len() == MAX_CHANGES {
+      if let Some(change) = self.changes.pop_front() {
+        self.changes_version = change.version;
+      }
+    }
+    self.changes.push_back(Change {
+      version,
+      start,
+      end,
+      len: text.chars().count(),
+    });
+  }
+
+  pub fn word_at(&self, index: usize) -> Option<(usize, usize)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut start = index;
+    while start > 0 && is_word(self.rope.char(start - 1)) {
+      start -= 1;
+    }
+    let mut end = index;
+    while end < self.rope.len_chars() && is_word(self.rope.char(end)) {
+      end += 1;
+    }
+    (start < end).then_some((start, end))
+  }
+
+  /// Transforms a char range of the document at `version` to the current version.
+  /// Returns `None` if the range was touched by any change since then or the changes are no longer tracked.
+  pub fn rebase(&self, version: i32, mut start: usize, mut end: usize) -> Option<(usize, usize)> {
+    if version < self.changes_version {
+      return None;
+    }
+    for change in self.changes.iter().filter(|change| change.version > version) {
+      if change.end <= start {
+        start = start - (change.end - change.start) + change.len;
+        end = end - (change.end - change.start) + change.len;
+      } else if change.start >= end {
+        continue;
+      } else {
+        return None;
+      }
+    }
+    Some((start, end))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use lsp_types::{Position, Range};
+  use ropey::Rope;
+
+  use super::Document;
+
+  fn document() -> Document {
+    Document::new(Rope::from_str("aaa\nbbb\nccc\n"), 1, "plaintext".to_string())
+  }
+
+  #[test]
+  fn rebase_unchanged() {
+    let document = document();
+    assert_eq!(document.rebase(1, 4, 7), Some((4, 7)));
+  }
+
+  #[test]
+  fn rebase_changes_outside() {
+    let mut document = document();
+    document.change(2, Some(Range::new(Position::new(0, 0), Position::new(0, 1))), "xyz");
+    document.change(3, Some(Range::new(Position::new(2, 0), Position::new(2, 3))), "");
+    document.change(4, Some(Range::new(Position::new(1, 0), Position::new(1, 0))), "_");
+    assert_eq!(document.rope.to_string(), "xyzaa\n_bbb\n\n");
+    assert_eq!(document.rebase(1, 4, 7), Some((7, 10)));
+    assert_eq!(document.rebase(2, 6, 9), Some((7, 10)));
+    assert_eq!(document.rebase(4, 7, 10), Some((7, 10)));
+  }
+
+  #[test]
+  fn rebase_changes_inside() {
+    let mut document = document();
+    document.change(2, Some(Range::new(Position::new(1, 1), Position::new(1, 1))), "x");
+    assert_eq!(document.rebase(1, 4, 7), None);
+    assert_eq!(document.rebase(2, 4, 8), Some((4, 8)));
+  }
+
+  #[test]
+  fn rebase_changes_overlapping() {
+    let mut document = document();
+    document.change(2, Some(Range::new(Position::new(0, 2), Position::new(1, 1))), "");
+    assert_eq!(document.rebase(1, 4, 7), None);
+  }
+
+  #[test]
+  fn word_at() {
+    let document = Document::new(Rope::from_str("let foo_bar = 1;\n"), 1, "rust".to_string());
+    assert_eq!(document.word_at(4), Some((4, 11)));
+    assert_eq!(document.word_at(11), Some((4, 11)));
+    assert_eq!(document.word_at(12), None);
+    assert_eq!(document.word_at(17), None);
+  }
+
+  #[test]
+  fn rebase_full_change() {
+    let mut document = document();
+    document.change(2, None, "aaa\nbbb\nccc\n");
+    assert_eq!(document.rebase(1, 4, 7), None);
+  }
+}
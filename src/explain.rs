@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use lsp_server::{RequestId, Response as LspResponse};
+use lsp_types::{request::ShowDocument, Location, MessageType, ProgressToken, ShowDocumentParams};
+use serde_json::Value;
+
+use crate::{
+  config::ExplainOutput, document::position_to_char, render_messages, trace, SelectionContent, State, NO_ANSWER_MESSAGE,
+};
+
+impl State {
+  pub fn explain(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let (location, prompt) = match TryInto::<[_; 2]>::try_into(arguments) {
+      Ok([location, prompt]) => (location, serde_json::from_value(prompt)?),
+      Err(arguments) => match TryInto::<[_; 1]>::try_into(arguments) {
+        Ok([location]) => (location, String::new()),
+        Err(arguments) => return Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
+      },
+    };
+    let location: Location = serde_json::from_value(location)?;
+    let document = self
+      .documents
+      .get(&location.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
+    let start_index = position_to_char(&document.rope, location.range.start);
+    let end_index = position_to_char(&document.rope, location.range.end);
+    let content = SelectionContent {
+      prompt,
+      selection: document.rope.slice(start_index..end_index).into(),
+      prefix: document.rope.slice(..start_index).into(),
+      suffix: document.rope.slice(end_index..).into(),
+    };
+    let messages = render_messages(&self.config.explain.messages, &content);
+    drop(document);
+    let chat = self.config.explain.model_config.get_chat(&self.scripts);
+    let output = self.config.explain.output;
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = location.uri.clone();
+    let future = async move {
+      let choice = state
+        .run_chat(&request_id_c, work_done_token, "Explain", chat, messages, false)
+        .await;
+      match choice.and_then(|choice| choice.ok_or_else(|| anyhow!(NO_ANSWER_MESSAGE))) {
+        Ok(explanation) => {
+          state.respond(LspResponse::new_ok(request_id_c.clone(), &explanation))?;
+          state
+            .show_answer(output, &format!("explain-{}.md", request_id_c), explanation)
+            .await?;
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+
+  pub async fn show_answer(&self, output: ExplainOutput, name: &str, answer: String) -> Result<()> {
+    match output {
+      ExplainOutput::Message => self.show_message(MessageType::INFO, answer),
+      ExplainOutput::Document if self.show_document => {
+        let (_, uri) = self.temp.write(name, &answer)?;
+        self
+          .outgoing
+          .request::<ShowDocument>(ShowDocumentParams {
+            uri,
+            external: Some(false),
+            take_focus: Some(true),
+            selection: None,
+          })
+          .await?;
+        Ok(())
+      }
+      ExplainOutput::Document => self.show_message(MessageType::INFO, answer),
+      ExplainOutput::None => Ok(()),
+    }
+  }
+}
@@ -0,0 +1,204 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::ExtractConfig;
+
+const FENCE: &str = "```";
+
+fn is_fence(line: &str) -> bool {
+  line.trim_start().starts_with(FENCE)
+}
+
+fn strip_fences(text: &str) -> Option<&str> {
+  let trimmed = text.trim();
+  let (first, rest) = trimmed.split_once('\n')?;
+  if !is_fence(first) {
+    return None;
+  }
+  let end = rest.rfind('\n').map_or(0, |index| index + 1);
+  if rest[end..].trim() != FENCE {
+    return None;
+  }
+  Some(&rest[..end])
+}
+
+fn first_code_block(text: &str) -> Option<&str> {
+  let mut start = None;
+  let mut offset = 0;
+  for line in text.split_inclusive('\n') {
+    if is_fence(line) {
+      match start {
+        None => start = Some(offset + line.len()),
+        Some(start) => return Some(&text[start..offset]),
+      }
+    }
+    offset += line.len();
+  }
+  None
+}
+
+/// Returns the info string and content of every fenced code block.
+pub fn code_blocks(text: &str) -> Vec<(&str, &str)> {
+  let mut blocks = Vec::new();
+  let mut start = None;
+  let mut offset = 0;
+  for line in text.split_inclusive('\n') {
+    if is_fence(line) {
+      match start {
+        None => start = Some((line.trim_start()[FENCE.len()..].trim(), offset + line.len())),
+        Some((info, start_offset)) => {
+          blocks.push((info, &text[start_offset..offset]));
+          start = None;
+        }
+      }
+    }
+    offset += line.len();
+  }
+  blocks
+}
+
+#[derive(Deserialize)]
+struct FileAnswer {
+  tag: String,
+  content: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileAnswers {
+  List(Vec<FileAnswer>),
+  Object { files: Vec<FileAnswer> },
+}
+
+/// Parses the tags and contents of the files from either a JSON array or an object with a `files` array.
+pub fn parse_files(value: Value) -> Result<Vec<(String, String)>> {
+  let (FileAnswers::List(files) | FileAnswers::Object { files }) = serde_json::from_value(value)?;
+  Ok(files.into_iter().map(|file| (file.tag, file.content)).collect())
+}
+
+fn unquote(text: &str) -> &str {
+  let text = text.trim();
+  text
+    .strip_prefix('"')
+    .and_then(|text| text.strip_suffix('"'))
+    .unwrap_or(text)
+}
+
+/// Whether an info string names the tag, on its own or after the language, and optionally quoted.
+pub fn has_tag(info: &str, tag: &str) -> bool {
+  unquote(info) == tag
+    || info
+      .split_once(char::is_whitespace)
+      .is_some_and(|(_, rest)| unquote(rest) == tag)
+}
+
+impl ExtractConfig {
+  pub fn extract(&self, text: String) -> String {
+    let extracted = match self {
+      ExtractConfig::None => None,
+      ExtractConfig::StripFences => strip_fences(&text),
+      ExtractConfig::FirstCodeBlock => first_code_block(&text),
+      ExtractConfig::Regex { pattern } => pattern
+        .0
+        .captures(&text)
+        .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+        .map(|m| m.as_str()),
+    };
+    match extracted {
+      Some(extracted) => extracted.to_string(),
+      None => text,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use regex::Regex;
+
+  use crate::config::{ExtractConfig, RegexConfig};
+
+  use serde_json::json;
+
+  use super::{code_blocks, has_tag, parse_files};
+
+  #[test]
+  fn none() {
+    let text = "```rust\nfn main() {}\n```".to_string();
+    assert_eq!(ExtractConfig::None.extract(text.clone()), text);
+  }
+
+  #[test]
+  fn strip_fences() {
+    assert_eq!(
+      ExtractConfig::StripFences.extract("```rust\nfn main() {\n}\n```\n".to_string()),
+      "fn main() {\n}\n"
+    );
+    assert_eq!(ExtractConfig::StripFences.extract("```\n```".to_string()), "");
+    assert_eq!(
+      ExtractConfig::StripFences.extract("fn main() {}\n".to_string()),
+      "fn main() {}\n"
+    );
+    assert_eq!(
+      ExtractConfig::StripFences.extract("Here is the code:\n```\nfn main() {}\n```".to_string()),
+      "Here is the code:\n```\nfn main() {}\n```"
+    );
+  }
+
+  #[test]
+  fn first_code_block() {
+    assert_eq!(
+      ExtractConfig::FirstCodeBlock
+        .extract("Here is the code:\n\n```rust\nfn main() {}\n```\n\n```\nother\n```\nDone.".to_string()),
+      "fn main() {}\n"
+    );
+    assert_eq!(
+      ExtractConfig::FirstCodeBlock.extract("fn main() {}".to_string()),
+      "fn main() {}"
+    );
+  }
+
+  #[test]
+  fn regex() {
+    let config = ExtractConfig::Regex {
+      pattern: RegexConfig(Regex::new(r"(?s)<code>(.*)</code>").unwrap()),
+    };
+    assert_eq!(
+      config.extract("Sure! <code>fn main() {}</code> Enjoy.".to_string()),
+      "fn main() {}"
+    );
+    assert_eq!(config.extract("fn main() {}".to_string()), "fn main() {}");
+  }
+
+  #[test]
+  fn tagged_code_blocks() {
+    assert_eq!(
+      code_blocks("Changes:\n\n```rust src/a.rs\nfn a() {}\n```\n\n```src/b.rs\n```\nDone.\n```\nunterminated\n"),
+      vec![("rust src/a.rs", "fn a() {}\n"), ("src/b.rs", "")]
+    );
+  }
+
+  #[test]
+  fn tags() {
+    assert!(has_tag("src/a.rs", "src/a.rs"));
+    assert!(has_tag("rust src/a.rs", "src/a.rs"));
+    assert!(has_tag("rust \"src/my file.rs:1-2\"", "src/my file.rs:1-2"));
+    assert!(has_tag("src/my file.rs", "src/my file.rs"));
+    assert!(!has_tag("rust src/my file.rs", "file.rs"));
+    assert!(!has_tag("rust src/a.rs", "a.rs"));
+  }
+
+  #[test]
+  fn files() {
+    let files = vec![("src/a.rs".to_string(), "fn a() {}\n".to_string())];
+    assert_eq!(
+      parse_files(json!({ "files": [{ "tag": "src/a.rs", "content": "fn a() {}\n" }] })).unwrap(),
+      files
+    );
+    assert_eq!(
+      parse_files(json!([{ "tag": "src/a.rs", "content": "fn a() {}\n" }])).unwrap(),
+      files
+    );
+    assert!(parse_files(json!({ "files": [{ "tag": "src/a.rs" }] })).is_err());
+  }
+}
@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use lsp_server::{RequestId, Response as LspResponse};
+use lsp_types::{ProgressToken, TextDocumentPositionParams};
+use serde_json::Value;
+
+use crate::{document::position_to_char, indent, render_messages, trace, SelectionContent, State};
+
+impl State {
+  pub fn generate(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let [position, prompt] = TryInto::<[_; 2]>::try_into(arguments)
+      .map_err(|arguments| anyhow!("Wrong number of arguments: {}", arguments.len()))?;
+    let position: TextDocumentPositionParams = serde_json::from_value(position)?;
+    let prompt: String = serde_json::from_value(prompt)?;
+    let uri = position.text_document.uri;
+    let document = self
+      .documents
+      .get(&uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+    let index = position_to_char(&document.rope, position.position);
+    let content = SelectionContent {
+      prompt,
+      selection: document.rope.slice(index..index).into(),
+      prefix: document.rope.slice(..index).into(),
+      suffix: document.rope.slice(index..).into(),
+    };
+    let messages = render_messages(&self.config.generate.messages, &content);
+    let version = document.version;
+    drop(document);
+    let chat = self.config.generate.model_config.get_chat(&self.scripts);
+    let extract = self.config.generate.extract.clone();
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = uri.clone();
+    let future = async move {
+      let choice = state
+        .run_chat(&request_id_c, work_done_token, "Generate", chat, messages, false)
+        .await;
+      match choice {
+        Ok(choice) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+          if let Some(choice) = choice {
+            state
+              .insert_text(uri, version, index, |line| {
+                indent::indent(
+                  &indent::dedent(&extract.extract(choice)),
+                  indent::indentation(line),
+                  false,
+                )
+              })
+              .await?;
+          }
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+}
@@ -0,0 +1,127 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use lsp_server::{RequestId, Response as LspResponse};
+use lsp_types::{
+  CreateFile, CreateFileOptions, DocumentChangeOperation, DocumentChanges, Location, OneOf,
+  OptionalVersionedTextDocumentIdentifier, ProgressToken, Range, ResourceOp, TextDocumentEdit, TextEdit, WorkspaceEdit,
+};
+use ramhorns::Content;
+use ropey::Rope;
+use serde_json::Value;
+
+use crate::{
+  document::{char_to_position, position_to_char},
+  path_to_uri, render_messages, trace, uri_to_path, RopeSliceContent, State, NO_ANSWER_MESSAGE,
+};
+
+#[derive(Content)]
+struct TestsContent<'a> {
+  language_id: String,
+  path: String,
+  tests: String,
+  selection: RopeSliceContent<'a>,
+}
+
+impl State {
+  pub fn generate_tests(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let [location] = TryInto::<[_; 1]>::try_into(arguments)
+      .map_err(|arguments| anyhow!("Wrong number of arguments: {}", arguments.len()))?;
+    let location: Location = serde_json::from_value(location)?;
+    let config = &self.config.generate_tests;
+    let test_path = uri_to_path(&location.uri)
+      .and_then(|path| config.test_path(&path))
+      .ok_or_else(|| anyhow!("No test path for {}", location.uri.as_str()))?;
+    let opened = self
+      .documents
+      .iter()
+      .find(|document| uri_to_path(document.key()).as_ref() == Some(&test_path));
+    let (test_uri, existing) = match opened {
+      Some(document) => (
+        document.key().clone(),
+        Some((document.rope.clone(), Some(document.version))),
+      ),
+      None if test_path.exists() => (
+        path_to_uri(&test_path)?,
+        Some((Rope::from_str(&fs::read_to_string(&test_path)?), None)),
+      ),
+      None if self.create_files => (path_to_uri(&test_path)?, None),
+      None => return Err(anyhow!("The client can't create {}", test_path.display())),
+    };
+    let document = self
+      .documents
+      .get(&location.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
+    let start_index = position_to_char(&document.rope, location.range.start);
+    let end_index = position_to_char(&document.rope, location.range.end);
+    let content = TestsContent {
+      language_id: document.language_id.clone(),
+      path: test_path.display().to_string(),
+      tests: existing.as_ref().map(|(rope, _)| rope.to_string()).unwrap_or_default(),
+      selection: document.rope.slice(start_index..end_index).into(),
+    };
+    let messages = render_messages(&config.messages, &content);
+    drop(document);
+    let chat = config.model_config.get_chat(&self.scripts);
+    let extract = config.extract.clone();
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = location.uri.clone();
+    let future = async move {
+      let choice = state
+        .run_chat(&request_id_c, work_done_token, "Generate tests", chat, messages, false)
+        .await;
+      let tests = match choice.and_then(|choice| choice.ok_or_else(|| anyhow!(NO_ANSWER_MESSAGE))) {
+        Ok(choice) => extract.extract(choice),
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+          return Ok(());
+        }
+      };
+      let edit = match existing {
+        Some((rope, version)) => {
+          let end = char_to_position(&rope, rope.len_chars());
+          let text = rope.to_string();
+          let separator = if text.is_empty() || text.ends_with("\n\n") {
+            ""
+          } else if text.ends_with('\n') {
+            "\n"
+          } else {
+            "\n\n"
+          };
+          let edits = vec![TextEdit::new(Range::new(end, end), format!("{}{}", separator, tests))];
+          state.workspace_edit(test_uri, version, edits, None)
+        }
+        None => WorkspaceEdit {
+          document_changes: Some(DocumentChanges::Operations(vec![
+            DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+              uri: test_uri.clone(),
+              options: Some(CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(false),
+              }),
+              annotation_id: None,
+            })),
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+              text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: test_uri,
+                version: None,
+              },
+              edits: vec![OneOf::Left(TextEdit::new(Range::default(), tests))],
+            }),
+          ])),
+          ..Default::default()
+        },
+      };
+      state.respond(LspResponse::new_ok(request_id_c, &edit))?;
+      state.apply_workspace_edit(edit).await
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+}
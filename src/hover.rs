@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use lsp_server::{Message, RequestId, Response as LspResponse};
+use lsp_types::{Hover, HoverContents, HoverParams, MarkupContent, MarkupKind, Range};
+
+use crate::{
+  chat::Chat,
+  document::{char_to_position, position_to_char},
+  render_messages, trace, SelectionContent, State,
+};
+
+fn markdown_hover(value: String, range: Range) -> Hover {
+  Hover {
+    contents: HoverContents::Markup(MarkupContent {
+      kind: MarkupKind::Markdown,
+      value,
+    }),
+    range: Some(range),
+  }
+}
+
+impl State {
+  pub fn hover(&self, request_id: RequestId, params: HoverParams) -> Result<()> {
+    let uri = params.text_document_position_params.text_document.uri;
+    let document = self
+      .documents
+      .get(&uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+    let index = position_to_char(&document.rope, params.text_document_position_params.position);
+    let Some((start_index, end_index)) = document.word_at(index) else {
+      self
+        .sender
+        .send(Message::Response(LspResponse::new_ok(request_id, None::<Hover>)))?;
+      return Ok(());
+    };
+    let range = Range::new(
+      char_to_position(&document.rope, start_index),
+      char_to_position(&document.rope, end_index),
+    );
+    if let Some(explanation) = document.hovers.get(&(start_index, end_index)) {
+      self.sender.send(Message::Response(LspResponse::new_ok(
+        request_id,
+        markdown_hover(explanation.clone(), range),
+      )))?;
+      return Ok(());
+    }
+
+    let hover_config = &self.config.hover;
+    {
+      let mut last_hover = self.last_hover.lock().map_err(|e| anyhow!(e.to_string()))?;
+      let now = Instant::now();
+      if last_hover
+        .is_some_and(|last_hover| now.duration_since(last_hover) < Duration::from_millis(hover_config.min_interval_ms))
+      {
+        self
+          .sender
+          .send(Message::Response(LspResponse::new_ok(request_id, None::<Hover>)))?;
+        return Ok(());
+      }
+      *last_hover = Some(now);
+    }
+
+    let start_line = (range.start.line as usize).saturating_sub(hover_config.context_lines);
+    let end_line = (range.end.line as usize + hover_config.context_lines + 1).min(document.rope.len_lines());
+    let context_start = document.rope.line_to_char(start_line);
+    let context_end = document.rope.line_to_char(end_line);
+    let content = SelectionContent {
+      prompt: String::new(),
+      selection: document.rope.slice(start_index..end_index).into(),
+      prefix: document.rope.slice(context_start..start_index).into(),
+      suffix: document.rope.slice(end_index..context_end).into(),
+    };
+    let messages = render_messages(&hover_config.messages, &content);
+    let version = document.version;
+    drop(document);
+    let chat = hover_config.model_config.get_chat(&self.scripts);
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = uri.clone();
+    let future = async move {
+      let choice = chat
+        .chat(state.client.clone(), messages)
+        .await
+        .map(|mut choices| choices.next());
+      match choice {
+        Ok(choice) => {
+          let hover = choice.map(|explanation| {
+            if let Some(mut document) = state.documents.get_mut(&uri) {
+              if document.version == version {
+                document.hovers.insert((start_index, end_index), explanation.clone());
+              }
+            }
+            markdown_hover(explanation, range)
+          });
+          state.respond(LspResponse::new_ok(request_id_c, hover))?;
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+}
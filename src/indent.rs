@@ -0,0 +1,67 @@
+pub fn indentation(line: &str) -> &str {
+  let end = line.find(|c: char| c != ' ' && c != '\t').unwrap_or(line.len());
+  &line[..end]
+}
+
+fn is_blank(line: &str) -> bool {
+  line.trim().is_empty()
+}
+
+pub fn dedent(text: &str) -> String {
+  let common = text
+    .lines()
+    .filter(|line| !is_blank(line))
+    .map(|line| indentation(line).len())
+    .min()
+    .unwrap_or(0);
+  text
+    .split_inclusive('\n')
+    .map(|line| {
+      if is_blank(line) {
+        line.trim_start_matches([' ', '\t'])
+      } else {
+        &line[common..]
+      }
+    })
+    .collect()
+}
+
+pub fn indent(text: &str, indentation: &str, first_line: bool) -> String {
+  text
+    .split_inclusive('\n')
+    .enumerate()
+    .map(|(i, line)| {
+      if (i > 0 || first_line) && !is_blank(line) {
+        format!("{}{}", indentation, line)
+      } else {
+        line.to_string()
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{dedent, indent, indentation};
+
+  #[test]
+  fn indentation_of_line() {
+    assert_eq!(indentation("    let a = 1;"), "    ");
+    assert_eq!(indentation("\t\tfoo"), "\t\t");
+    assert_eq!(indentation("  "), "  ");
+    assert_eq!(indentation("foo"), "");
+  }
+
+  #[test]
+  fn dedent_text() {
+    assert_eq!(dedent("    a\n      b\n\n    c\n"), "a\n  b\n\nc\n");
+    assert_eq!(dedent("a\n  b"), "a\n  b");
+    assert_eq!(dedent(""), "");
+  }
+
+  #[test]
+  fn indent_text() {
+    assert_eq!(indent("a\n  b\n\nc\n", "  ", false), "a\n    b\n\n  c\n");
+    assert_eq!(indent("a\nb", "\t", true), "\ta\n\tb");
+  }
+}
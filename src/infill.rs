@@ -54,3 +54,80 @@ impl Infill for () {
     Ok(iter::empty())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use serde_json::{json, Value};
+
+  use crate::{
+    config::CompletionConfig,
+    mock::Scripts,
+    stub::{stub_server, TIMEOUT},
+  };
+
+  use super::Infill;
+
+  /// Completes between `fn main() {` and `}` with the provider configured by `config`.
+  async fn infill(config: Value) -> String {
+    let config: CompletionConfig = serde_json::from_value(config).unwrap();
+    let client = Arc::new(reqwest::Client::new());
+    let infill = config.get_infill(&Scripts::default());
+    let mut choices = infill
+      .infill(client, "fn main() {\n  ".to_string(), "\n}\n".to_string())
+      .await
+      .unwrap();
+    choices.next().unwrap()
+  }
+
+  #[tokio::test]
+  async fn providers() {
+    let (url, requests) = stub_server(json!({ "content": "llama" }));
+    let completion = infill(json!({ "provider": "LlamaCpp", "config": { "url": url } })).await;
+    assert_eq!(completion, "llama");
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["input_prefix"], "fn main() {\n  ");
+    assert_eq!(request["input_suffix"], "\n}\n");
+
+    let (url, requests) = stub_server(json!({ "response": "ollama", "prompt_eval_count": 10, "eval_count": 2 }));
+    let completion = infill(json!({ "provider": "Ollama", "config": { "url": url, "model": "qwen2.5-coder" } })).await;
+    assert_eq!(completion, "ollama");
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["model"], "qwen2.5-coder");
+    assert_eq!(request["prompt"], "fn main() {\n  ");
+    assert_eq!(request["suffix"], "\n}\n");
+    assert_eq!(request["stream"], false);
+
+    // Setting a variable here would race with tests reading the environment in parallel, so use one that Cargo sets
+    // before the tests start.
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "role": "assistant", "content": "mistral" } }],
+      "usage": { "prompt_tokens": 10, "completion_tokens": 2 }
+    }));
+    let completion = infill(json!({
+      "provider": "Mistral",
+      "config": { "url": url, "api_key_env": "CARGO_PKG_NAME", "model": "codestral-latest" }
+    }))
+    .await;
+    assert_eq!(completion, "mistral");
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["model"], "codestral-latest");
+    assert_eq!(request["prompt"], "fn main() {\n  ");
+    assert_eq!(request["suffix"], "\n}\n");
+
+    let (url, requests) = stub_server(json!({ "choices": [{ "text": "openai" }] }));
+    let completion = infill(json!({
+      "provider": "OpenAICompletions",
+      "config": { "url": url },
+      "template": "<PRE>{{{ prefix }}}<SUF>{{{ suffix }}}<MID>"
+    }))
+    .await;
+    assert_eq!(completion, "openai");
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["prompt"], "<PRE>fn main() {\n  <SUF>\n}\n<MID>");
+
+    let completion = infill(json!({ "provider": "Mock", "config": { "responses": ["mock"] } })).await;
+    assert_eq!(completion, "mock");
+  }
+}
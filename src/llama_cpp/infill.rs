@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
   config::{LlamaCpp, ModelConfig},
   infill::Infill,
+  trace,
+  usage::{self, Usage},
 };
 
 #[derive(Clone, PartialEq, Debug, Serialize)]
@@ -21,9 +23,19 @@ struct InfillRequest<'a> {
   seed: Option<u32>,
 }
 
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct Timings {
+  prompt_n: u64,
+  predicted_n: u64,
+}
+
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 struct InfillResponse {
   content: String,
+  #[serde(default)]
+  model: Option<String>,
+  #[serde(default)]
+  timings: Option<Timings>,
 }
 
 impl Infill for ModelConfig<LlamaCpp> {
@@ -34,8 +46,9 @@ impl Infill for ModelConfig<LlamaCpp> {
     } else {
       request
     };
-    let response = request
-      .json(&InfillRequest {
+    let response: InfillResponse = trace::post(
+      request,
+      &InfillRequest {
         input_prefix: prefix,
         input_suffix: suffix,
         temperature: self.generation_config.temperature,
@@ -43,11 +56,13 @@ impl Infill for ModelConfig<LlamaCpp> {
         max_tokens: self.generation_config.max_tokens,
         stop: &self.generation_config.stop,
         seed: self.generation_config.seed,
-      })
-      .send()
-      .await?
-      .json::<InfillResponse>()
-      .await?;
+      },
+    )
+    .await?;
+    if let Some(timings) = response.timings {
+      let model = usage::model_name(None, response.model.as_deref(), &self.url);
+      usage::record(model, Usage::new(timings.prompt_n, timings.predicted_n));
+    }
 
     Ok(iter::once(response.content))
   }
@@ -1,36 +1,88 @@
 mod chat;
+mod commit_message;
 mod config;
+mod declaration;
+mod diff;
+mod document;
+mod explain;
+mod extract;
+mod generate;
+mod generate_tests;
+mod hover;
+mod indent;
 mod infill;
 mod llama_cpp;
 mod mistral;
+mod mock;
 mod ollama;
 mod openai;
+mod outgoing;
+mod review;
+mod rewrite_files;
+mod schema;
+mod session;
+#[cfg(test)]
+mod stub;
+mod temp;
+mod tools;
+mod trace;
+mod usage;
 
-use std::{collections::HashMap, env, fs::File, io::BufReader, sync::Arc};
+use std::{
+  collections::HashMap,
+  env, fs,
+  future::Future,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{atomic::AtomicUsize, Arc, Mutex},
+  time::Instant,
+};
 
 use anyhow::{anyhow, Result};
-use chat::Chat;
+use chat::{Chat, ChatMessage, PartialAnswer};
 use clap::Command;
-use config::Config;
+use config::{ChatModelConfig, Config, ExtractConfig, MessageConfig, ResponseFormatConfig, RewriteConfig};
 use crossbeam_channel::Sender;
 use dashmap::DashMap;
 use derive_more::From;
+use document::{apply_text_edits, char_to_position, position_to_char, Document};
 use infill::Infill;
-use lsp_server::{Connection, ErrorCode, Message, Request as LspRequest, RequestId, Response as LspResponse};
+use lsp_server::{
+  Connection, ErrorCode, Message, Notification as LspNotification, Request as LspRequest, RequestId,
+  Response as LspResponse, ResponseError,
+};
 use lsp_types::{
-  notification::{Cancel, DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Exit, Notification},
-  request::{ApplyWorkspaceEdit, ExecuteCommand, InlineCompletionRequest, Request},
-  ApplyWorkspaceEditParams, CancelParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
-  DidOpenTextDocumentParams, DocumentChanges, ExecuteCommandOptions, ExecuteCommandParams, InitializeParams,
-  InlineCompletionItem, InlineCompletionParams, InlineCompletionResponse, Location, NumberOrString, OneOf,
-  OptionalVersionedTextDocumentIdentifier, Range, ServerCapabilities, TextDocumentEdit, TextDocumentSyncKind, TextEdit,
-  Uri, WorkDoneProgressOptions, WorkspaceEdit,
+  notification::{
+    Cancel, DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument, Exit, Notification,
+    Progress, ShowMessage, WorkDoneProgressCancel,
+  },
+  request::{
+    ApplyWorkspaceEdit, CodeActionRequest, ExecuteCommand, HoverRequest, InlineCompletionRequest, Request,
+    ShowDocument, ShowMessageRequest, WorkDoneProgressCreate,
+  },
+  AnnotatedTextEdit, ApplyWorkspaceEditParams, CancelParams, ChangeAnnotation, CodeAction, CodeActionKind,
+  CodeActionOptions, CodeActionOrCommand, CodeActionParams, CodeActionProviderCapability, CodeActionResponse,
+  Command as LspCommand, DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+  DidSaveTextDocumentParams, DocumentChanges, ExecuteCommandOptions, ExecuteCommandParams, HoverParams,
+  HoverProviderCapability, InitializeParams, InlineCompletionItem, InlineCompletionParams, InlineCompletionResponse,
+  Location, MessageActionItem, MessageType, NumberOrString, OneOf, OptionalVersionedTextDocumentIdentifier,
+  ProgressParams, ProgressParamsValue, ProgressToken, Range, ResourceOperationKind, ServerCapabilities,
+  ShowDocumentParams, ShowMessageParams, ShowMessageRequestParams, TextDocumentEdit, TextDocumentSyncKind,
+  TextDocumentSyncOptions, TextDocumentSyncSaveOptions, TextEdit, Uri, WorkDoneProgress, WorkDoneProgressBegin,
+  WorkDoneProgressCancelParams, WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressOptions,
+  WorkDoneProgressReport, WorkspaceEdit,
 };
+use mock::Scripts;
+use outgoing::Outgoing;
 use ramhorns::{encoding::Encoder, Content, Template};
 use reqwest::Client;
 use ropey::{Rope, RopeSlice};
 use serde_json::Value;
-use tokio::task::JoinHandle;
+use session::Session;
+use temp::TempDirectory;
+use tokio::{sync::oneshot, task::JoinHandle};
+use tools::{Tools, MAX_TOOL_ROUNDS};
+use url::Url;
 
 #[derive(From)]
 struct RopeSliceContent<'a>(RopeSlice<'a>);
@@ -71,21 +123,62 @@ struct SelectionContent<'a> {
   suffix: RopeSliceContent<'a>,
 }
 
-#[derive(Debug)]
-struct Document {
-  rope: Rope,
-  version: i32,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct State {
   document_changes: bool,
+  change_annotations: bool,
+  create_files: bool,
+  show_document: bool,
+  work_done_progress: bool,
   sender: Arc<Sender<Message>>,
+  outgoing: Arc<Outgoing>,
   client: Arc<Client>,
-  config: Config,
+  config: Arc<Config>,
   documents: Arc<DashMap<Uri, Document>>,
   tasks: Arc<DashMap<RequestId, JoinHandle<Result<()>>>>,
-  templates: Vec<Template<'static>>,
+  progress_tokens: Arc<DashMap<RequestId, ProgressToken>>,
+  last_hover: Arc<Mutex<Option<Instant>>>,
+  reviews: Arc<DashMap<Uri, JoinHandle<Result<()>>>>,
+  workspace_folders: Arc<Vec<PathBuf>>,
+  sessions: Arc<DashMap<String, Session>>,
+  next_session: Arc<AtomicUsize>,
+  scripts: Arc<Scripts>,
+  trace: Option<Arc<trace::Sink>>,
+  usage: Arc<usage::Totals>,
+  temp: Arc<TempDirectory>,
+}
+
+fn command_action(title: &str, kind: CodeActionKind, command: &str, arguments: Vec<Value>) -> CodeActionOrCommand {
+  CodeActionOrCommand::CodeAction(CodeAction {
+    title: title.to_string(),
+    kind: Some(kind),
+    command: Some(LspCommand::new(title.to_string(), command.to_string(), Some(arguments))),
+    ..Default::default()
+  })
+}
+
+fn render_messages(messages: &[MessageConfig], content: &impl Content) -> Vec<(String, String)> {
+  messages
+    .iter()
+    .map(|message| (message.role.clone(), message.content.0.render(content)))
+    .collect()
+}
+
+fn uri_to_path(uri: &Uri) -> Option<PathBuf> {
+  Url::parse(uri.as_str()).ok()?.to_file_path().ok()
+}
+
+/// The path override patterns are matched against: the file path of the document, or else the decoded path of its URI.
+fn document_path(uri: &Uri) -> String {
+  match uri_to_path(uri) {
+    Some(path) => path.display().to_string(),
+    None => uri.path().as_estr().decode().into_string_lossy().into_owned(),
+  }
+}
+
+fn path_to_uri(path: &Path) -> Result<Uri> {
+  let url = Url::from_file_path(path).map_err(|_| anyhow!("Invalid file path: {}", path.display()))?;
+  Ok(Uri::from_str(url.as_str())?)
 }
 
 impl State {
@@ -100,23 +193,24 @@ impl State {
         )
       })?;
 
-    let index = document
-      .rope
-      .line_to_char(params.text_document_position.position.line as usize)
-      + params.text_document_position.position.character as usize;
+    let index = position_to_char(&document.rope, params.text_document_position.position);
     let prefix = document.rope.slice(..index).to_string();
     let suffix = document.rope.slice(index..).to_string();
 
-    let infill = self.config.get_infill();
-    let client = self.client.clone();
-    let sender = self.sender.clone();
-    let tasks = self.tasks.clone();
+    let infill = self
+      .config
+      .get_infill_config(
+        &document.language_id,
+        &document_path(&params.text_document_position.text_document.uri),
+      )
+      .get_infill(&self.scripts);
+    let state = self.clone();
     let request_id_c = request_id.clone();
+    let document_uri = params.text_document_position.text_document.uri.clone();
     let future = async move {
-      let completions = infill.infill(client, prefix, suffix).await;
+      let completions = infill.infill(state.client.clone(), prefix, suffix).await;
       match completions {
         Result::Ok(completions) => {
-          tasks.remove(&request_id_c);
           let range = Range::new(
             params.text_document_position.position,
             params.text_document_position.position,
@@ -131,191 +225,967 @@ impl State {
               insert_text_format: None,
             })
             .collect();
-          sender.send(Message::Response(LspResponse::new_ok(
+          state.respond(LspResponse::new_ok(
             request_id_c,
             InlineCompletionResponse::Array(completion_items),
-          )))?;
+          ))?;
         }
         Result::Err(error) => {
-          tasks.remove(&request_id_c);
-          sender.send(Message::Response(LspResponse::new_err(
-            request_id_c,
-            ErrorCode::RequestFailed as i32,
-            format!("Failed to get response: {}", error),
-          )))?;
+          state.respond_failed(request_id_c, error)?;
         }
       }
       Ok(())
     };
-    let handle = tokio::task::spawn(future);
-    self.tasks.insert(request_id, handle);
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
     Ok(())
   }
 
-  fn rewrite(&self, request_id: RequestId, arguments: Vec<Value>) -> Result<()> {
+  fn rewrite(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    if let [location] = arguments.as_slice() {
+      let location: Location = serde_json::from_value(location.clone())?;
+      return self.rewrite_with_prompt_request(request_id, location, work_done_token);
+    }
     match TryInto::<[_; 2]>::try_into(arguments) {
       Ok([location, prompt]) => {
         let location: Location = serde_json::from_value(location)?;
         let prompt: String = serde_json::from_value(prompt)?;
-        let document = self
-          .documents
-          .get(&location.uri)
-          .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
-        let start_index =
-          document.rope.line_to_char(location.range.start.line as usize) + location.range.start.character as usize;
-        let end_index =
-          document.rope.line_to_char(location.range.end.line as usize) + location.range.end.character as usize;
-        let content = SelectionContent {
-          prompt,
-          selection: document.rope.slice(start_index..end_index).into(),
-          prefix: document.rope.slice(..start_index).into(),
-          suffix: document.rope.slice(end_index..).into(),
-        };
-        let messages = self
-          .config
-          .rewrite
-          .messages
+        self.rewrite_selection(request_id, location, prompt, work_done_token, |rewrite_config| {
+          Ok(&rewrite_config.messages)
+        })
+      }
+      Err(arguments) => Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
+    }
+  }
+
+  fn rewrite_with_prompt_request(
+    &self,
+    request_id: RequestId,
+    location: Location,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let document = self
+      .documents
+      .get(&location.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
+    let prompts = &self
+      .config
+      .get_rewrite_config(&document.language_id, &document_path(&location.uri))
+      .prompts;
+    if prompts.is_empty() {
+      return Err(anyhow!("No rewrite prompts configured"));
+    }
+    let params = ShowMessageRequestParams {
+      typ: MessageType::INFO,
+      message: "Rewrite prompt".to_string(),
+      actions: Some(
+        prompts
           .iter()
-          .zip(self.templates.iter())
-          .map(|(message, template)| (message.role.clone(), template.render(&content)))
-          .collect();
-        let version = document.version;
-        let chat = self.config.get_rewrite();
-        let client = self.client.clone();
-        let document_changes = self.document_changes;
-        let sender = self.sender.clone();
-        let tasks = self.tasks.clone();
-        let documents = self.documents.clone();
-        let request_id_c = request_id.clone();
-        let future = async move {
-          let choices = chat.chat(client.clone(), messages).await;
-          match choices {
-            Ok(mut choices) => {
-              tasks.remove(&request_id_c);
-              sender.send(Message::Response(LspResponse::new_ok(request_id_c, ())))?;
-              if let Some(choice) = choices.next() {
-                if !documents
-                  .get(&location.uri)
-                  .map_or(false, |document| document.version == version)
-                {
-                  return Ok(());
-                }
-                let edit_params = if document_changes {
-                  ApplyWorkspaceEditParams {
-                    label: None,
-                    edit: WorkspaceEdit {
-                      changes: None,
-                      document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
-                        text_document: OptionalVersionedTextDocumentIdentifier {
-                          uri: location.uri,
-                          version: Some(version),
-                        },
-                        edits: vec![OneOf::Left(TextEdit {
-                          range: location.range,
-                          new_text: choice,
-                        })],
-                      }])),
-                      change_annotations: None,
-                    },
-                  }
-                } else {
-                  ApplyWorkspaceEditParams {
-                    label: None,
-                    edit: WorkspaceEdit {
-                      changes: Some(HashMap::from([(
-                        location.uri,
-                        vec![TextEdit {
-                          range: location.range,
-                          new_text: choice,
-                        }],
-                      )])),
-                      document_changes: None,
-                      change_annotations: None,
-                    },
-                  }
-                };
-                sender.send(Message::Request(LspRequest::new(
-                  RequestId::from(0),
-                  ApplyWorkspaceEdit::METHOD.to_string(),
-                  edit_params,
-                )))?;
-              }
-            }
-            Err(error) => {
-              tasks.remove(&request_id_c);
-              sender.send(Message::Response(LspResponse::new_err(
-                request_id_c,
-                ErrorCode::RequestFailed as i32,
-                format!("Failed to get response: {}", error),
-              )))?;
+          .map(|prompt| MessageActionItem {
+            title: prompt.clone(),
+            properties: Default::default(),
+          })
+          .collect(),
+      ),
+    };
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let future = async move {
+      match state.outgoing.request::<ShowMessageRequest>(params).await {
+        Ok(Some(action)) => {
+          let result = state.rewrite_selection(
+            request_id_c.clone(),
+            location,
+            action.title,
+            work_done_token,
+            |rewrite_config| Ok(&rewrite_config.messages),
+          );
+          if let Err(error) = result {
+            state.respond(LspResponse::new_err(
+              request_id_c,
+              ErrorCode::RequestFailed as i32,
+              error.to_string(),
+            ))?;
+          }
+        }
+        Ok(None) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+        }
+        Err(error) => {
+          state.respond(LspResponse::new_err(
+            request_id_c,
+            ErrorCode::RequestFailed as i32,
+            format!("Failed to get prompt: {}", error),
+          ))?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, future);
+    Ok(())
+  }
+
+  fn rewrite_preset(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    match TryInto::<[_; 2]>::try_into(arguments) {
+      Ok([location, title]) => {
+        let location: Location = serde_json::from_value(location)?;
+        let title: String = serde_json::from_value(title)?;
+        let unknown = self.documents.get(&location.uri).is_some_and(|document| {
+          !self
+            .config
+            .get_rewrite_config(&document.language_id, &document_path(&location.uri))
+            .presets
+            .iter()
+            .any(|preset| preset.title == title)
+        });
+        if unknown {
+          self.sender.send(Message::Response(LspResponse::new_err(
+            request_id,
+            ErrorCode::InvalidParams as i32,
+            format!("Unknown preset: {}", title),
+          )))?;
+          return Ok(());
+        }
+        self.rewrite_selection(request_id, location, String::new(), work_done_token, |rewrite_config| {
+          rewrite_config
+            .presets
+            .iter()
+            .find(|preset| preset.title == title)
+            .map(|preset| preset.messages.as_slice())
+            .ok_or_else(|| anyhow!("Unknown preset: {}", title))
+        })
+      }
+      Err(arguments) => Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
+    }
+  }
+
+  fn rewrite_selection<'a>(
+    &'a self,
+    request_id: RequestId,
+    location: Location,
+    prompt: String,
+    work_done_token: Option<ProgressToken>,
+    select_messages: impl FnOnce(&'a RewriteConfig) -> Result<&'a [MessageConfig]>,
+  ) -> Result<()> {
+    let document = self
+      .documents
+      .get(&location.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
+    let start_index = position_to_char(&document.rope, location.range.start);
+    let end_index = position_to_char(&document.rope, location.range.end);
+    let content = SelectionContent {
+      prompt,
+      selection: document.rope.slice(start_index..end_index).into(),
+      prefix: document.rope.slice(..start_index).into(),
+      suffix: document.rope.slice(end_index..).into(),
+    };
+    let rewrite_config = self
+      .config
+      .get_rewrite_config(&document.language_id, &document_path(&location.uri));
+    let messages = render_messages(select_messages(rewrite_config)?, &content);
+    let version = document.version;
+    let chat = rewrite_config.model_config.get_chat(&self.scripts);
+    let preview = rewrite_config.preview;
+    let tools = rewrite_config.tools;
+    let stream = rewrite_config.stream;
+    if stream && tools {
+      return Err(anyhow!("Tools can't be used with streaming"));
+    }
+    let extract = rewrite_config.extract.clone();
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let document_uri = location.uri.clone();
+    let future = async move {
+      let choice = if stream {
+        state
+          .run_chat_stream(&request_id_c, work_done_token, "Rewrite", chat, messages)
+          .await
+      } else {
+        state
+          .run_chat(&request_id_c, work_done_token, "Rewrite", chat, messages, tools)
+          .await
+      };
+      match choice {
+        Ok(choice) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+          if let Some(choice) = choice {
+            let new_text = extract.extract(choice);
+            if preview {
+              state
+                .preview_edit(location.uri, version, start_index, end_index, new_text)
+                .await?;
+            } else if let Some((version, edits)) =
+              state.selection_edits(&location.uri, version, start_index, end_index, &new_text)?
+            {
+              state.apply_edit(location.uri, version, edits, None).await?;
             }
           }
-          Ok(())
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, trace::with_document(document_uri, future));
+    Ok(())
+  }
+
+  fn usage(&self, request_id: RequestId) -> Result<()> {
+    let report = self.usage.report(&self.config.usage);
+    self.show_message(MessageType::INFO, report.message())?;
+    self
+      .sender
+      .send(Message::Response(LspResponse::new_ok(request_id, report)))?;
+    Ok(())
+  }
+
+  /// Inserts the text returned by `new_text` for the current line at `index` of the document at `version`.
+  async fn insert_text(
+    &self,
+    uri: Uri,
+    version: i32,
+    index: usize,
+    new_text: impl FnOnce(&str) -> String,
+  ) -> Result<()> {
+    let Some(document) = self.documents.get(&uri) else {
+      return Ok(());
+    };
+    let Some((index, _)) = document.rebase(version, index, index) else {
+      drop(document);
+      return self.show_message(MessageType::WARNING, GENERATE_CONFLICT_MESSAGE);
+    };
+    let line = document.rope.line(document.rope.char_to_line(index)).to_string();
+    let position = char_to_position(&document.rope, index);
+    let version = document.version;
+    drop(document);
+    let edits = vec![TextEdit {
+      range: Range::new(position, position),
+      new_text: new_text(&line),
+    }];
+    self.apply_edit(uri, version, edits, None).await
+  }
+
+  fn workspace_edit(
+    &self,
+    uri: Uri,
+    version: Option<i32>,
+    edits: Vec<TextEdit>,
+    annotation: Option<ChangeAnnotation>,
+  ) -> WorkspaceEdit {
+    self.documents_edit(vec![(uri, version, edits)], annotation)
+  }
+
+  fn documents_edit(
+    &self,
+    documents: Vec<(Uri, Option<i32>, Vec<TextEdit>)>,
+    annotation: Option<ChangeAnnotation>,
+  ) -> WorkspaceEdit {
+    let mut merged: Vec<(Uri, Option<i32>, Vec<TextEdit>)> = Vec::new();
+    for (uri, version, edits) in documents {
+      match merged.iter_mut().find(|(other, _, _)| *other == uri) {
+        Some((_, _, other_edits)) => other_edits.extend(edits),
+        None => merged.push((uri, version, edits)),
+      }
+    }
+    let documents = merged;
+    if self.document_changes {
+      let annotation_id = annotation.as_ref().map(|_| REWRITE_ANNOTATION.to_string());
+      WorkspaceEdit {
+        changes: None,
+        document_changes: Some(DocumentChanges::Edits(
+          documents
+            .into_iter()
+            .map(|(uri, version, edits)| TextDocumentEdit {
+              text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+              edits: edits
+                .into_iter()
+                .map(|text_edit| match annotation_id {
+                  Some(ref annotation_id) => OneOf::Right(AnnotatedTextEdit {
+                    text_edit,
+                    annotation_id: annotation_id.clone(),
+                  }),
+                  None => OneOf::Left(text_edit),
+                })
+                .collect(),
+            })
+            .collect(),
+        )),
+        change_annotations: annotation.map(|annotation| HashMap::from([(REWRITE_ANNOTATION.to_string(), annotation)])),
+      }
+    } else {
+      WorkspaceEdit {
+        changes: Some(documents.into_iter().map(|(uri, _, edits)| (uri, edits)).collect()),
+        document_changes: None,
+        change_annotations: None,
+      }
+    }
+  }
+
+  async fn apply_edit(
+    &self,
+    uri: Uri,
+    version: i32,
+    edits: Vec<TextEdit>,
+    annotation: Option<ChangeAnnotation>,
+  ) -> Result<()> {
+    self
+      .apply_workspace_edit(self.workspace_edit(uri, Some(version), edits, annotation))
+      .await
+  }
+
+  async fn apply_workspace_edit(&self, edit: WorkspaceEdit) -> Result<()> {
+    let edit_params = ApplyWorkspaceEditParams { label: None, edit };
+    match self.outgoing.request::<ApplyWorkspaceEdit>(edit_params).await {
+      Ok(response) if response.applied => Ok(()),
+      Ok(response) => self.show_message(
+        MessageType::WARNING,
+        format!(
+          "Failed to apply the edit: {}",
+          response.failure_reason.as_deref().unwrap_or("rejected by the client")
+        ),
+      ),
+      Err(error) => self.show_message(MessageType::ERROR, format!("Failed to apply the edit: {}", error)),
+    }
+  }
+
+  /// Rebases the selection between `start_index` and `end_index` at `version` on the current document and diffs it
+  /// with `new_text`, returning the current version and the edits, or `None` if the selection was changed.
+  fn selection_edits(
+    &self,
+    uri: &Uri,
+    version: i32,
+    start_index: usize,
+    end_index: usize,
+    new_text: &str,
+  ) -> Result<Option<(i32, Vec<TextEdit>)>> {
+    let Some(document) = self.documents.get(uri) else {
+      return Ok(None);
+    };
+    let Some((start_index, end_index)) = document.rebase(version, start_index, end_index) else {
+      drop(document);
+      self.show_message(MessageType::WARNING, REWRITE_CONFLICT_MESSAGE)?;
+      return Ok(None);
+    };
+    let selection = document.rope.slice(start_index..end_index).to_string();
+    let start = char_to_position(&document.rope, start_index);
+    Ok(Some((document.version, diff::diff_edits(&selection, new_text, start))))
+  }
+
+  async fn preview_edit(
+    &self,
+    uri: Uri,
+    version: i32,
+    start_index: usize,
+    end_index: usize,
+    new_text: String,
+  ) -> Result<()> {
+    let Some((current_version, edits)) = self.selection_edits(&uri, version, start_index, end_index, &new_text)? else {
+      return Ok(());
+    };
+    if self.change_annotations {
+      let annotation = ChangeAnnotation {
+        label: "Rewrite".to_string(),
+        needs_confirmation: Some(true),
+        description: None,
+      };
+      return self.apply_edit(uri, current_version, edits, Some(annotation)).await;
+    }
+
+    let confirmed = if self.show_document {
+      let text = {
+        let document = self
+          .documents
+          .get(&uri)
+          .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+        let mut rope = document.rope.clone();
+        apply_text_edits(&mut rope, &edits);
+        rope.to_string()
+      };
+      let file_name = uri
+        .path()
+        .segments()
+        .next_back()
+        .map(|segment| segment.decode().into_string_lossy().into_owned())
+        .unwrap_or_default();
+      let (path, preview_uri) = self.temp.write(&format!("{}-{}", current_version, file_name), &text)?;
+      let result = self
+        .confirm_preview(Some(preview_uri), PREVIEW_MESSAGE.to_string())
+        .await;
+      let _ = fs::remove_file(&path);
+      result?
+    } else {
+      // Without a way to show the rewritten document, the question shows the rewritten selection.
+      self
+        .confirm_preview(None, format!("{}\n\n{}", PREVIEW_MESSAGE, new_text))
+        .await?
+    };
+    // The document may have been changed while the user was deciding.
+    if confirmed {
+      if let Some((version, edits)) = self.selection_edits(&uri, version, start_index, end_index, &new_text)? {
+        self.apply_edit(uri, version, edits, None).await?;
+      }
+    }
+    Ok(())
+  }
+
+  async fn run_chat(
+    &self,
+    request_id: &RequestId,
+    work_done_token: Option<ProgressToken>,
+    title: &str,
+    chat: impl Chat,
+    messages: Vec<(String, String)>,
+    tools: bool,
+  ) -> Result<Option<String>> {
+    self.begin_progress(request_id, work_done_token, title).await;
+    let choice = if tools {
+      self.chat_with_tools(chat, messages).await
+    } else {
+      chat
+        .chat(self.client.clone(), messages)
+        .await
+        .map(|mut choices| choices.next())
+    };
+    self.end_progress(request_id)?;
+    choice
+  }
+
+  /// Like `run_chat`, but streams the answer and reports the number of received lines as progress.
+  async fn run_chat_stream(
+    &self,
+    request_id: &RequestId,
+    work_done_token: Option<ProgressToken>,
+    title: &str,
+    chat: impl Chat,
+    messages: Vec<(String, String)>,
+  ) -> Result<Option<String>> {
+    self.begin_progress(request_id, work_done_token, title).await;
+    let mut lines = 0;
+    let answer = chat
+      .chat_stream(self.client.clone(), messages, |delta| {
+        let count = delta.matches('\n').count();
+        if count > 0 {
+          lines += count;
+          self.report_progress(request_id, format!("{} lines", lines));
+        }
+      })
+      .await;
+    self.end_progress(request_id)?;
+    // Like an empty list of choices, an empty answer leaves the selection alone.
+    answer.map(|answer| answer.filter(|answer| !answer.is_empty()))
+  }
+
+  /// Asks for an answer in `response_format`, retrying with the validation error if the answer does not match it.
+  async fn chat_json(
+    &self,
+    chat: impl Chat,
+    mut messages: Vec<(String, String)>,
+    response_format: &ResponseFormatConfig,
+    extract: &ExtractConfig,
+  ) -> Result<Value> {
+    let mut error = String::new();
+    for _ in 0..=FORMAT_RETRIES {
+      let choice = chat
+        .chat_with_format(self.client.clone(), messages.clone(), response_format.clone())
+        .await
+        .map(|mut choices| choices.next())?
+        .unwrap_or_default();
+      error = match serde_json::from_str::<Value>(extract.extract(choice.clone()).trim()) {
+        Ok(value) => match response_format.validate(&value) {
+          Ok(()) => return Ok(value),
+          Err(error) => error,
+        },
+        Err(error) => error.to_string(),
+      };
+      messages.push(("assistant".to_string(), choice));
+      messages.push(("user".to_string(), format!("{} {}", FORMAT_RETRY_MESSAGE, error)));
+    }
+    Err(anyhow!("Invalid answer: {}", error))
+  }
+
+  async fn chat_with_tools(&self, chat: impl Chat, messages: Vec<(String, String)>) -> Result<Option<String>> {
+    let tools = Tools::new(self.documents.clone(), self.workspace_folders.clone());
+    let mut messages = messages
+      .into_iter()
+      .map(|(role, content)| ChatMessage::Message { role, content })
+      .collect::<Vec<_>>();
+    for _ in 0..MAX_TOOL_ROUNDS {
+      let reply = chat
+        .chat_with_tools(self.client.clone(), messages.clone(), tools.definitions())
+        .await?;
+      if reply.tool_calls.is_empty() {
+        return Ok(reply.content);
+      }
+      // The tools read files, so they run outside of the async workers.
+      let tools_c = tools.clone();
+      let tool_calls = reply.tool_calls.clone();
+      let results = tokio::task::spawn_blocking(move || {
+        tool_calls
+          .iter()
+          .map(|tool_call| ChatMessage::ToolResult {
+            id: tool_call.id.clone(),
+            content: tools_c.call(tool_call),
+          })
+          .collect::<Vec<_>>()
+      })
+      .await?;
+      messages.push(ChatMessage::ToolCalls {
+        content: reply.content,
+        tool_calls: reply.tool_calls,
+      });
+      messages.extend(results);
+    }
+    Err(anyhow!("No answer after {} rounds of tool calls", MAX_TOOL_ROUNDS))
+  }
+
+  fn progress(&self, token: ProgressToken, progress: WorkDoneProgress) -> Result<()> {
+    self.sender.send(Message::Notification(LspNotification::new(
+      Progress::METHOD.to_string(),
+      ProgressParams {
+        token,
+        value: ProgressParamsValue::WorkDone(progress),
+      },
+    )))?;
+    Ok(())
+  }
+
+  async fn begin_progress(&self, request_id: &RequestId, work_done_token: Option<ProgressToken>, title: &str) {
+    let token = match work_done_token {
+      Some(token) => token,
+      None if self.work_done_progress => {
+        let token = ProgressToken::String(format!("famulus-{}", request_id));
+        let result = self
+          .outgoing
+          .request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams { token: token.clone() })
+          .await;
+        if let Err(error) = result {
+          log::warn!("Failed to create progress: {}", error);
+          return;
+        }
+        token
+      }
+      None => return,
+    };
+    let begin = WorkDoneProgress::Begin(WorkDoneProgressBegin {
+      title: title.to_string(),
+      cancellable: Some(true),
+      message: None,
+      percentage: None,
+    });
+    if let Err(error) = self.progress(token.clone(), begin) {
+      log::warn!("Failed to begin progress: {}", error);
+      return;
+    }
+    self.progress_tokens.insert(request_id.clone(), token);
+  }
+
+  fn report_progress(&self, request_id: &RequestId, message: String) {
+    let Some(token) = self.progress_tokens.get(request_id).map(|token| token.clone()) else {
+      return;
+    };
+    let report = WorkDoneProgress::Report(WorkDoneProgressReport {
+      cancellable: Some(true),
+      message: Some(message),
+      percentage: None,
+    });
+    if let Err(error) = self.progress(token, report) {
+      log::warn!("Failed to report progress: {}", error);
+    }
+  }
+
+  fn end_progress(&self, request_id: &RequestId) -> Result<()> {
+    if let Some((_, token)) = self.progress_tokens.remove(request_id) {
+      self.progress(token, WorkDoneProgress::End(WorkDoneProgressEnd { message: None }))?;
+    }
+    Ok(())
+  }
+
+  fn show_message(&self, typ: MessageType, message: impl Into<String>) -> Result<()> {
+    self.sender.send(Message::Notification(LspNotification::new(
+      ShowMessage::METHOD.to_string(),
+      ShowMessageParams {
+        typ,
+        message: message.into(),
+      },
+    )))?;
+    Ok(())
+  }
+
+  async fn confirm_preview(&self, preview_uri: Option<Uri>, message: String) -> Result<bool> {
+    if let Some(preview_uri) = preview_uri {
+      self
+        .outgoing
+        .request::<ShowDocument>(ShowDocumentParams {
+          uri: preview_uri,
+          external: Some(false),
+          take_focus: Some(true),
+          selection: None,
+        })
+        .await?;
+    }
+    let action = self
+      .outgoing
+      .request::<ShowMessageRequest>(ShowMessageRequestParams {
+        typ: MessageType::INFO,
+        message,
+        actions: Some(vec![
+          MessageActionItem {
+            title: APPLY_ACTION.to_string(),
+            properties: Default::default(),
+          },
+          MessageActionItem {
+            title: DISCARD_ACTION.to_string(),
+            properties: Default::default(),
+          },
+        ]),
+      })
+      .await?;
+    Ok(action.is_some_and(|action| action.title == APPLY_ACTION))
+  }
+
+  fn code_action(&self, request_id: RequestId, params: CodeActionParams) -> Result<()> {
+    let document = self
+      .documents
+      .get(&params.text_document.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", params.text_document.uri.as_str()))?;
+    let rewrite_config = self
+      .config
+      .get_rewrite_config(&document.language_id, &document_path(&params.text_document.uri));
+    let location = serde_json::to_value(Location::new(params.text_document.uri.clone(), params.range))?;
+    let mut actions = CodeActionResponse::new();
+    if params.range.start != params.range.end {
+      if rewrite_config.model_config != ChatModelConfig::Empty {
+        // Without prompts, there is nothing to ask the user for.
+        if !rewrite_config.prompts.is_empty() {
+          actions.push(command_action(
+            REWRITE_TITLE,
+            CodeActionKind::REFACTOR_REWRITE,
+            REWRITE_COMMAND,
+            vec![location.clone()],
+          ));
+        }
+        actions.extend(rewrite_config.presets.iter().map(|preset| {
+          command_action(
+            &preset.title,
+            CodeActionKind::REFACTOR_REWRITE,
+            REWRITE_PRESET_COMMAND,
+            vec![location.clone(), Value::String(preset.title.clone())],
+          )
+        }));
+      }
+      if self.config.explain.model_config != ChatModelConfig::Empty {
+        actions.push(command_action(
+          EXPLAIN_TITLE,
+          CodeActionKind::from(EXPLAIN_KIND),
+          EXPLAIN_COMMAND,
+          vec![location],
+        ));
+      }
+    }
+    for diagnostic in &params.context.diagnostics {
+      if let Some(fix) = review::diagnostic_fix(diagnostic) {
+        // The fix is for the reviewed version, so it moves with the changes made since then.
+        let Some((start_index, end_index)) = document.rebase(fix.version, fix.start, fix.end) else {
+          continue;
         };
-        let handle = tokio::task::spawn(future);
-        self.tasks.insert(request_id, handle);
-        Ok(())
+        let range = Range::new(
+          char_to_position(&document.rope, start_index),
+          char_to_position(&document.rope, end_index),
+        );
+        let edit = TextEdit::new(range, fix.replacement);
+        actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+          title: REVIEW_FIX_TITLE.to_string(),
+          kind: Some(CodeActionKind::QUICKFIX),
+          diagnostics: Some(vec![diagnostic.clone()]),
+          edit: Some(self.workspace_edit(
+            params.text_document.uri.clone(),
+            Some(document.version),
+            vec![edit],
+            None,
+          )),
+          is_preferred: Some(true),
+          ..Default::default()
+        }));
       }
-      Err(arguments) => Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
     }
+    if let Some(only) = &params.context.only {
+      actions.retain(|action| match action {
+        CodeActionOrCommand::CodeAction(CodeAction { kind: Some(kind), .. }) => only.iter().any(|only| {
+          kind == only
+            || kind
+              .as_str()
+              .strip_prefix(only.as_str())
+              .is_some_and(|rest| rest.starts_with('.'))
+        }),
+        _ => false,
+      });
+    }
+    self
+      .sender
+      .send(Message::Response(LspResponse::new_ok(request_id, actions)))?;
+    Ok(())
   }
 
   fn did_open_text_document(&mut self, params: DidOpenTextDocumentParams) -> Result<()> {
-    let file = File::open(params.text_document.uri.path().as_str())?;
-    let reader = BufReader::new(file);
-    let rope = Rope::from_reader(reader)?;
+    let rope = Rope::from_str(&params.text_document.text);
     self.documents.insert(
       params.text_document.uri,
-      Document {
-        rope,
-        version: params.text_document.version,
-      },
+      Document::new(rope, params.text_document.version, params.text_document.language_id),
     );
     Ok(())
   }
 
-  fn did_close_text_document(&mut self, params: DidCloseTextDocumentParams) {
+  fn did_close_text_document(&mut self, params: DidCloseTextDocumentParams) -> Result<()> {
     self.documents.remove(&params.text_document.uri);
+    if let Some((_, handle)) = self.reviews.remove(&params.text_document.uri) {
+      handle.abort();
+    }
+    if self.config.review.enabled {
+      self.publish_diagnostics(params.text_document.uri, Vec::new(), None)?;
+    }
+    Ok(())
   }
 
   fn did_change_text_document(&mut self, params: DidChangeTextDocumentParams) -> Result<()> {
+    let mut document = self
+      .documents
+      .get_mut(&params.text_document.uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", params.text_document.uri.as_str()))?;
     for change in params.content_changes {
-      if let Some(range) = change.range {
-        let mut document = self
-          .documents
-          .get_mut(&params.text_document.uri)
-          .ok_or_else(|| anyhow!("Missing document: {}", params.text_document.uri.as_str()))?;
-        let start_index = document.rope.line_to_char(range.start.line as usize) + range.start.character as usize;
-        let end_index = document.rope.line_to_char(range.end.line as usize) + range.end.character as usize;
-        document.rope.remove(start_index..end_index);
-        document.rope.insert(start_index, &change.text);
-        document.version = params.text_document.version;
+      document.change(params.text_document.version, change.range, &change.text);
+    }
+    document.version = params.text_document.version;
+    Ok(())
+  }
+
+  fn request(&self, request: LspRequest) -> Result<()> {
+    if request.method == InlineCompletionRequest::METHOD {
+      let (request_id, params) = request.extract::<InlineCompletionParams>(InlineCompletionRequest::METHOD)?;
+      self.inline_completion_request(request_id, params)?;
+    } else if request.method == HoverRequest::METHOD {
+      let (request_id, params) = request.extract::<HoverParams>(HoverRequest::METHOD)?;
+      self.hover(request_id, params)?;
+    } else if request.method == CodeActionRequest::METHOD {
+      let (request_id, params) = request.extract::<CodeActionParams>(CodeActionRequest::METHOD)?;
+      self.code_action(request_id, params)?;
+    } else if request.method == ExecuteCommand::METHOD {
+      let (request_id, params) = request.extract::<ExecuteCommandParams>(ExecuteCommand::METHOD)?;
+      if params.command.as_str() == REWRITE_COMMAND {
+        self.rewrite(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == REWRITE_PRESET_COMMAND {
+        self.rewrite_preset(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == REWRITE_FILES_COMMAND {
+        self.rewrite_files(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == EXPLAIN_COMMAND {
+        self.explain(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == SESSION_START_COMMAND {
+        self.session_start(request_id)?;
+      } else if params.command.as_str() == SESSION_SEND_COMMAND {
+        self.session_send(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == SESSION_RESET_COMMAND {
+        self.session_reset(request_id, params.arguments)?;
+      } else if params.command.as_str() == USAGE_COMMAND {
+        self.usage(request_id)?;
+      } else if params.command.as_str() == GENERATE_COMMAND {
+        self.generate(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == DOCUMENT_COMMAND {
+        self.document(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == GENERATE_TESTS_COMMAND {
+        self.generate_tests(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
+      } else if params.command.as_str() == COMMIT_MESSAGE_COMMAND {
+        self.commit_message(
+          request_id,
+          params.arguments,
+          params.work_done_progress_params.work_done_token,
+        )?;
       } else {
-        let rope = Rope::from_str(&change.text);
-        self.documents.insert(
-          params.text_document.uri.clone(),
-          Document {
-            rope,
-            version: params.text_document.version,
-          },
-        );
+        self.sender.send(Message::Response(LspResponse::new_err(
+          request_id,
+          ErrorCode::InvalidRequest as i32,
+          format!("Unknown command: {}", params.command),
+        )))?;
       }
+    } else {
+      self.sender.send(Message::Response(LspResponse::new_err(
+        request.id,
+        ErrorCode::MethodNotFound as i32,
+        format!("Unknown method: {}", request.method),
+      )))?;
     }
     Ok(())
   }
 
-  fn cancel(&self, params: CancelParams) {
+  fn notification(&mut self, notification: LspNotification) -> Result<()> {
+    if notification.method == DidOpenTextDocument::METHOD {
+      let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+      self.did_open_text_document(params)?;
+    } else if notification.method == DidCloseTextDocument::METHOD {
+      let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+      self.did_close_text_document(params)?;
+    } else if notification.method == DidSaveTextDocument::METHOD {
+      let params: DidSaveTextDocumentParams = serde_json::from_value(notification.params)?;
+      self.did_save_text_document(params)?;
+    } else if notification.method == DidChangeTextDocument::METHOD {
+      let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+      self.did_change_text_document(params)?;
+    } else if notification.method == Cancel::METHOD {
+      let params: CancelParams = serde_json::from_value(notification.params)?;
+      self.cancel(params)?;
+    } else if notification.method == WorkDoneProgressCancel::METHOD {
+      let params: WorkDoneProgressCancelParams = serde_json::from_value(notification.params)?;
+      self.cancel_work_done_progress(params)?;
+    }
+    Ok(())
+  }
+
+  fn cancel(&self, params: CancelParams) -> Result<()> {
     let id: RequestId = match params.id {
       NumberOrString::Number(id) => id.into(),
       NumberOrString::String(id) => id.into(),
     };
-    if let Some((_, handle)) = self.tasks.remove(&id) {
+    self.cancel_task(&id)
+  }
+
+  fn cancel_work_done_progress(&self, params: WorkDoneProgressCancelParams) -> Result<()> {
+    let id = self
+      .progress_tokens
+      .iter()
+      .find(|entry| *entry.value() == params.token)
+      .map(|entry| entry.key().clone());
+    if let Some(id) = id {
+      self.cancel_task(&id)?;
+    }
+    Ok(())
+  }
+
+  /// Runs `future` with the trace sink and the usage totals of the server.
+  fn scoped<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+    trace::with_sink(self.trace.clone(), usage::with_totals(self.usage.clone(), future))
+  }
+
+  /// Runs a request in the background. It can be cancelled until it is answered with `respond`.
+  fn spawn_task(&self, request_id: RequestId, future: impl Future<Output = Result<()>> + Send + 'static) {
+    let (start_sender, start_receiver) = oneshot::channel();
+    let handle = tokio::task::spawn(self.scoped(async move {
+      // Wait until the handle is registered, so that a quick answer cannot leave a stale handle behind.
+      let _ = start_receiver.await;
+      future.await
+    }));
+    self.tasks.insert(request_id, handle);
+    let _ = start_sender.send(());
+  }
+
+  /// Answers a request started with `spawn_task`, unless it was already answered or cancelled.
+  fn respond(&self, response: LspResponse) -> Result<()> {
+    if self.tasks.remove(&response.id).is_some() {
+      self.sender.send(Message::Response(response))?;
+    }
+    Ok(())
+  }
+
+  /// Answers a request started with `spawn_task` with the error of its model request, and the part of the answer
+  /// received before it, if any.
+  fn respond_failed(&self, request_id: RequestId, error: anyhow::Error) -> Result<()> {
+    self.respond(LspResponse {
+      id: request_id,
+      result: None,
+      error: Some(ResponseError {
+        code: ErrorCode::RequestFailed as i32,
+        message: format!("Failed to get response: {}", error),
+        data: error
+          .downcast_ref::<PartialAnswer>()
+          .map(|partial| serde_json::json!({ "partial": partial.answer })),
+      }),
+    })
+  }
+
+  fn cancel_task(&self, id: &RequestId) -> Result<()> {
+    if let Some((_, handle)) = self.tasks.remove(id) {
       handle.abort();
+      self.sender.send(Message::Response(LspResponse::new_err(
+        id.clone(),
+        ErrorCode::RequestCanceled as i32,
+        "Request cancelled".to_string(),
+      )))?;
     }
+    self.end_progress(id)
+  }
+
+  fn abort_tasks(&self) {
+    self.tasks.iter().for_each(|task| task.value().abort());
+    self.tasks.clear();
+    self.reviews.iter().for_each(|review| review.value().abort());
+    self.reviews.clear();
   }
 }
 
 const REWRITE_COMMAND: &str = "famulus-rewrite";
+const REWRITE_TITLE: &str = "Rewrite with AI…";
+const REWRITE_ANNOTATION: &str = "famulus-rewrite";
+const GENERATE_CONFLICT_MESSAGE: &str = "Generation discarded: the document was changed at the cursor";
+const REWRITE_CONFLICT_MESSAGE: &str = "Rewrite discarded: the selection was changed while the request was running";
+const PREVIEW_MESSAGE: &str = "Apply the rewrite?";
+const APPLY_ACTION: &str = "Apply";
+const DISCARD_ACTION: &str = "Discard";
+const REWRITE_PRESET_COMMAND: &str = "famulus-rewrite-preset";
+const REWRITE_FILES_COMMAND: &str = "famulus-rewrite-files";
+const GENERATE_COMMAND: &str = "famulus-generate";
+const DOCUMENT_COMMAND: &str = "famulus-document";
+const GENERATE_TESTS_COMMAND: &str = "famulus-generate-tests";
+const COMMIT_MESSAGE_COMMAND: &str = "famulus-commit-message";
+const EXPLAIN_COMMAND: &str = "famulus-explain";
+const SESSION_START_COMMAND: &str = "famulus-session-start";
+const SESSION_SEND_COMMAND: &str = "famulus-session-send";
+const SESSION_RESET_COMMAND: &str = "famulus-session-reset";
+const USAGE_COMMAND: &str = "famulus-usage";
+const EXPLAIN_TITLE: &str = "Explain with AI";
+const EXPLAIN_KIND: &str = "famulus.explain";
+const FORMAT_RETRIES: usize = 1;
+const FORMAT_RETRY_MESSAGE: &str = "Your answer does not match the requested JSON format, answer again. Error:";
+const REVIEW_FIX_TITLE: &str = "Apply suggested fix";
+const NO_ANSWER_MESSAGE: &str = "The model gave no answer";
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() -> Result<()> {
@@ -329,88 +1199,1268 @@ async fn main() -> Result<()> {
     .get_matches();
 
   let (connection, io_threads) = Connection::stdio();
-  let server_capabilities = ServerCapabilities {
-    execute_command_provider: Some(ExecuteCommandOptions {
-      commands: vec![REWRITE_COMMAND.to_string()],
-      work_done_progress_options: WorkDoneProgressOptions {
-        work_done_progress: Some(false),
-      },
-    }),
-    inline_completion_provider: Some(OneOf::Left(true)),
-    text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Kind(
-      TextDocumentSyncKind::INCREMENTAL,
-    )),
-    ..Default::default()
-  };
-  let initialize_params = connection.initialize(serde_json::to_value(server_capabilities)?)?;
+  run(connection).await?;
+  io_threads.join().map_err(|e| e.into())
+}
+
+/// Initializes the server and handles messages until the client exits.
+async fn run(connection: Connection) -> Result<()> {
+  let (initialize_id, initialize_params) = connection.initialize_start()?;
   let initialize_params = serde_json::from_value::<InitializeParams>(initialize_params)?;
-  let document_changes = initialize_params
+  let workspace_edit = initialize_params
     .capabilities
     .workspace
-    .and_then(|workspace| workspace.workspace_edit)
+    .as_ref()
+    .and_then(|workspace| workspace.workspace_edit.as_ref());
+  let document_changes = workspace_edit
     .and_then(|workspace_edit| workspace_edit.document_changes)
     .unwrap_or_default();
+  let change_annotations =
+    document_changes && workspace_edit.is_some_and(|workspace_edit| workspace_edit.change_annotation_support.is_some());
+  let create_files = document_changes
+    && workspace_edit
+      .and_then(|workspace_edit| workspace_edit.resource_operations.as_ref())
+      .is_some_and(|resource_operations| resource_operations.contains(&ResourceOperationKind::Create));
+  let show_document = initialize_params
+    .capabilities
+    .window
+    .as_ref()
+    .and_then(|window| window.show_document.as_ref())
+    .is_some_and(|show_document| show_document.support);
+  let work_done_progress = initialize_params
+    .capabilities
+    .window
+    .as_ref()
+    .and_then(|window| window.work_done_progress)
+    .unwrap_or_default();
+  #[allow(deprecated)]
+  let workspace_folders = match initialize_params.workspace_folders {
+    Some(workspace_folders) => workspace_folders.into_iter().map(|folder| folder.uri).collect(),
+    None => initialize_params.root_uri.into_iter().collect::<Vec<_>>(),
+  }
+  .iter()
+  .filter_map(uri_to_path)
+  .collect::<Vec<_>>();
   let initialization_options = initialize_params
     .initialization_options
     .ok_or_else(|| anyhow!("Missing initialization options"))?;
   let config = serde_json::from_value::<Config>(initialization_options)?;
+  let trace = trace::Sink::open(&config.trace)?.map(Arc::new);
 
-  let templates = config
-    .rewrite
-    .messages
-    .iter()
-    .map(|message| Template::new(message.content.clone()).map_err(|e| e.into()))
-    .collect::<Result<_>>()?;
+  let server_capabilities = ServerCapabilities {
+    execute_command_provider: Some(ExecuteCommandOptions {
+      commands: vec![
+        REWRITE_COMMAND.to_string(),
+        REWRITE_PRESET_COMMAND.to_string(),
+        REWRITE_FILES_COMMAND.to_string(),
+        GENERATE_COMMAND.to_string(),
+        DOCUMENT_COMMAND.to_string(),
+        GENERATE_TESTS_COMMAND.to_string(),
+        COMMIT_MESSAGE_COMMAND.to_string(),
+        EXPLAIN_COMMAND.to_string(),
+        SESSION_START_COMMAND.to_string(),
+        SESSION_SEND_COMMAND.to_string(),
+        SESSION_RESET_COMMAND.to_string(),
+        USAGE_COMMAND.to_string(),
+      ],
+      work_done_progress_options: WorkDoneProgressOptions {
+        work_done_progress: Some(true),
+      },
+    }),
+    inline_completion_provider: Some(OneOf::Left(true)),
+    hover_provider: config.hover.enabled.then_some(HoverProviderCapability::Simple(true)),
+    code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+      code_action_kinds: Some(vec![
+        CodeActionKind::REFACTOR_REWRITE,
+        CodeActionKind::from(EXPLAIN_KIND),
+        CodeActionKind::QUICKFIX,
+      ]),
+      ..Default::default()
+    })),
+    text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Options(
+      TextDocumentSyncOptions {
+        open_close: Some(true),
+        change: Some(TextDocumentSyncKind::INCREMENTAL),
+        save: config
+          .review
+          .enabled
+          .then_some(TextDocumentSyncSaveOptions::Supported(true)),
+        ..Default::default()
+      },
+    )),
+    ..Default::default()
+  };
+  connection.initialize_finish(
+    initialize_id,
+    serde_json::json!({
+      "capabilities": server_capabilities,
+    }),
+  )?;
 
   let mut state = State {
     document_changes,
-    sender: Arc::new(connection.sender),
+    change_annotations,
+    create_files,
+    show_document,
+    work_done_progress,
+    sender: Arc::new(connection.sender.clone()),
+    outgoing: Arc::new(Outgoing::new(connection.sender.clone())),
     client: Arc::new(reqwest::Client::new()),
-    config,
+    config: Arc::new(config),
     documents: Default::default(),
     tasks: Default::default(),
-    templates,
+    progress_tokens: Default::default(),
+    last_hover: Default::default(),
+    reviews: Default::default(),
+    workspace_folders: Arc::new(workspace_folders),
+    sessions: Default::default(),
+    next_session: Default::default(),
+    scripts: Default::default(),
+    trace,
+    usage: Default::default(),
+    temp: Default::default(),
   };
 
   for msg in &connection.receiver {
     match msg {
       Message::Request(request) => {
-        if request.method == InlineCompletionRequest::METHOD {
-          let (request_id, params) = request.extract::<InlineCompletionParams>(InlineCompletionRequest::METHOD)?;
-          state.inline_completion_request(request_id, params)?;
-        } else if request.method == ExecuteCommand::METHOD {
-          let (request_id, params) = request.extract::<ExecuteCommandParams>(ExecuteCommand::METHOD)?;
-          if params.command.as_str() == REWRITE_COMMAND {
-            state.rewrite(request_id, params.arguments)?;
-          } else {
-            state.sender.send(Message::Response(LspResponse::new_err(
-              request_id,
-              ErrorCode::InvalidRequest as i32,
-              format!("Unknown command: {}", params.command),
-            )))?;
-          }
+        if connection.handle_shutdown(&request)? {
+          state.abort_tasks();
+          return Ok(());
+        }
+        let request_id = request.id.clone();
+        if let Err(error) = state.request(request) {
+          state.sender.send(Message::Response(LspResponse::new_err(
+            request_id,
+            ErrorCode::RequestFailed as i32,
+            error.to_string(),
+          )))?;
         }
       }
       Message::Notification(notification) => {
-        if notification.method == DidOpenTextDocument::METHOD {
-          let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
-          state.did_open_text_document(params)?;
-        } else if notification.method == DidCloseTextDocument::METHOD {
-          let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
-          state.did_close_text_document(params);
-        } else if notification.method == DidChangeTextDocument::METHOD {
-          let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
-          state.did_change_text_document(params)?;
-        } else if notification.method == Cancel::METHOD {
-          let params: CancelParams = serde_json::from_value(notification.params)?;
-          state.cancel(params);
-        } else if notification.method == Exit::METHOD {
+        if notification.method == Exit::METHOD {
+          state.abort_tasks();
           return Ok(());
         }
+        let method = notification.method.clone();
+        if let Err(error) = state.notification(notification) {
+          log::warn!("Failed to handle {}: {}", method, error);
+        }
       }
-      _ => {}
+      Message::Response(response) => state.outgoing.complete(response),
     }
   }
 
-  io_threads.join().map_err(|e| e.into())
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::VecDeque, env, fs, process, thread};
+
+  use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+  use serde_json::{json, Value};
+  use url::Url;
+
+  use crate::stub::{silent_server, stub_server, TIMEOUT};
+
+  use super::{run, MAX_TOOL_ROUNDS};
+
+  const URI: &str = "file:///project/src/main.rs";
+
+  fn range(start: (u32, u32), end: (u32, u32)) -> Value {
+    json!({
+      "start": { "line": start.0, "character": start.1 },
+      "end": { "line": end.0, "character": end.1 }
+    })
+  }
+
+  /// An LSP client driving the server over an in-memory connection.
+  struct Client {
+    connection: Connection,
+    server: thread::JoinHandle<()>,
+    next_id: i32,
+    /// Messages received while waiting for a response.
+    received: VecDeque<Message>,
+    capabilities: Value,
+  }
+
+  impl Client {
+    fn start(options: Value) -> Self {
+      Client::start_with(json!({}), options)
+    }
+
+    /// Starts a server for a client with the given capabilities.
+    fn start_with(capabilities: Value, options: Value) -> Self {
+      let (server, connection) = Connection::memory();
+      let server = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(run(server)).unwrap();
+      });
+      let mut client = Client {
+        connection,
+        server,
+        next_id: 0,
+        received: VecDeque::new(),
+        capabilities: Value::Null,
+      };
+      let response = client.request(
+        "initialize",
+        json!({ "capabilities": capabilities, "initializationOptions": options }),
+      );
+      client.capabilities = response.result.unwrap()["capabilities"].clone();
+      client.notify("initialized", json!({}));
+      client
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+      self
+        .connection
+        .sender
+        .send(Message::Notification(Notification::new(method.to_string(), params)))
+        .unwrap();
+    }
+
+    fn receive(&mut self) -> Message {
+      self
+        .received
+        .pop_front()
+        .unwrap_or_else(|| self.connection.receiver.recv_timeout(TIMEOUT).unwrap())
+    }
+
+    fn send_request(&mut self, method: &str, params: Value) -> RequestId {
+      self.next_id += 1;
+      let id = RequestId::from(self.next_id);
+      self
+        .connection
+        .sender
+        .send(Message::Request(Request::new(id.clone(), method.to_string(), params)))
+        .unwrap();
+      id
+    }
+
+    /// Waits for the response to `id`, keeping other messages for later.
+    fn response(&mut self, id: RequestId) -> Response {
+      let index = self
+        .received
+        .iter()
+        .position(|message| matches!(message, Message::Response(response) if response.id == id));
+      if let Some(Message::Response(response)) = index.and_then(|index| self.received.remove(index)) {
+        return response;
+      }
+      loop {
+        match self.connection.receiver.recv_timeout(TIMEOUT).unwrap() {
+          Message::Response(response) if response.id == id => return response,
+          message => self.received.push_back(message),
+        }
+      }
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Response {
+      let id = self.send_request(method, params);
+      self.response(id)
+    }
+
+    /// Waits for a request from the server, skipping notifications.
+    fn server_request(&mut self, method: &str) -> Request {
+      loop {
+        match self.receive() {
+          Message::Request(request) => {
+            assert_eq!(request.method, method);
+            return request;
+          }
+          Message::Response(response) => panic!("Unexpected response: {:?}", response),
+          Message::Notification(_) => {}
+        }
+      }
+    }
+
+    fn respond(&self, id: RequestId, result: Value) {
+      self
+        .connection
+        .sender
+        .send(Message::Response(Response::new_ok(id, result)))
+        .unwrap();
+    }
+
+    fn open(&self, text: &str) {
+      self.notify(
+        "textDocument/didOpen",
+        json!({
+          "textDocument": { "uri": URI, "languageId": "rust", "version": 1, "text": text }
+        }),
+      );
+    }
+
+    fn change(&self, version: i32, range: Value, text: &str) {
+      self.notify(
+        "textDocument/didChange",
+        json!({
+          "textDocument": { "uri": URI, "version": version },
+          "contentChanges": [{ "range": range, "text": text }]
+        }),
+      );
+    }
+
+    fn cancel(&self, id: &RequestId) {
+      self.notify("$/cancelRequest", json!({ "id": id }));
+    }
+
+    fn inline_completion(&mut self, line: u32, character: u32) -> RequestId {
+      self.send_request(
+        "textDocument/inlineCompletion",
+        json!({
+          "textDocument": { "uri": URI },
+          "position": { "line": line, "character": character },
+          "context": { "triggerKind": 1 }
+        }),
+      )
+    }
+
+    fn rewrite(&mut self, range: Value, prompt: &str) -> Response {
+      self.request(
+        "workspace/executeCommand",
+        json!({
+          "command": "famulus-rewrite",
+          "arguments": [{ "uri": URI, "range": range }, prompt]
+        }),
+      )
+    }
+
+    /// Waits for a `workspace/applyEdit` request, applies it and returns its edit.
+    fn apply_edit(&mut self) -> Value {
+      let request = self.server_request("workspace/applyEdit");
+      self.respond(request.id, json!({ "applied": true }));
+      request.params["edit"].clone()
+    }
+
+    fn shutdown(mut self) {
+      let response = self.request("shutdown", Value::Null);
+      assert!(response.error.is_none(), "{:?}", response.error);
+      self.notify("exit", Value::Null);
+      self.server.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn lifecycle() {
+    let (url, requests) = stub_server(json!({ "content": "println!();" }));
+    let mut client = Client::start(json!({
+      "infill": { "provider": "LlamaCpp", "config": { "url": url } },
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["  let b = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+      }
+    }));
+    assert_eq!(client.capabilities["inlineCompletionProvider"], true);
+    let commands = client.capabilities["executeCommandProvider"]["commands"]
+      .as_array()
+      .unwrap();
+    assert!(commands.contains(&json!("famulus-rewrite")));
+
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    client.change(2, range((1, 2), (1, 12)), "");
+    let id = client.inline_completion(1, 2);
+    let response = client.response(id);
+    assert_eq!(
+      response.result.unwrap(),
+      json!([{ "insertText": "println!();", "range": range((1, 2), (1, 2)) }])
+    );
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["input_prefix"], "fn main() {\n  ");
+    assert_eq!(request["input_suffix"], "\n}\n");
+
+    client.change(3, range((1, 2), (1, 2)), "let a = 1;");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({
+        "changes": {
+          URI: [
+            { "range": range((1, 6), (1, 7)), "newText": "b" },
+            { "range": range((1, 10), (1, 11)), "newText": "2" }
+          ]
+        }
+      })
+    );
+
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-unknown", "arguments": [] }),
+    );
+    assert_eq!(response.error.unwrap().code, ErrorCode::InvalidRequest as i32);
+    let response = client.request("textDocument/unknown", json!({}));
+    assert_eq!(response.error.unwrap().code, ErrorCode::MethodNotFound as i32);
+
+    client.shutdown();
+  }
+
+  #[test]
+  fn overrides() {
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["SELECT 1;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+      },
+      "overrides": [{
+        "patterns": ["**/my dir/*.sql"],
+        "rewrite": {
+          "model_config": { "provider": "Mock", "config": { "responses": ["SELECT 2;\n"] } },
+          "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+        }
+      }]
+    }));
+    let uri = "file:///project/my%20dir/query.sql";
+    client.notify(
+      "textDocument/didOpen",
+      json!({ "textDocument": { "uri": uri, "languageId": "sql", "version": 1, "text": "SELECT 0;\n" } }),
+    );
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-rewrite", "arguments": [{ "uri": uri, "range": range((0, 0), (1, 0)) }, ""] }),
+    );
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { uri: [{ "range": range((0, 7), (0, 8)), "newText": "2" }] } })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn cancel() {
+    let mut client = Client::start(json!({
+      "infill": { "provider": "LlamaCpp", "config": { "url": silent_server() } },
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["  let b = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let id = client.inline_completion(1, 2);
+    client.cancel(&id);
+    let response = client.response(id.clone());
+    assert_eq!(response.error.unwrap().code, ErrorCode::RequestCanceled as i32);
+
+    // Cancelling an answered request must not answer it again.
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    client.apply_edit();
+    client.cancel(&response.id);
+    client.cancel(&id);
+    client.request("textDocument/unknown", json!({}));
+    assert!(
+      !client
+        .received
+        .iter()
+        .any(|message| matches!(message, Message::Response(_))),
+      "{:?}",
+      client.received
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn hover() {
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "role": "assistant", "content": "A local variable." } }]
+    }));
+    let mut client = Client::start(json!({
+      "hover": {
+        "enabled": true,
+        "model_config": { "provider": "OpenAI", "config": { "url": url } },
+        "messages": [{ "role": "user", "content": "{{ selection }}" }],
+        "min_interval_ms": 500
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let hover = |client: &mut Client| {
+      client.request(
+        "textDocument/hover",
+        json!({ "textDocument": { "uri": URI }, "position": { "line": 1, "character": 6 } }),
+      )
+    };
+    let explanation = json!({
+      "contents": { "kind": "markdown", "value": "A local variable." },
+      "range": range((1, 6), (1, 7))
+    });
+    assert_eq!(hover(&mut client).result.unwrap(), explanation);
+    // The explanation is cached for the version of the document.
+    assert_eq!(hover(&mut client).result.unwrap(), explanation);
+    assert_eq!(requests.try_iter().count(), 1);
+
+    client.change(2, range((1, 10), (1, 11)), "2");
+    // Inside the interval, no new request is sent.
+    assert_eq!(hover(&mut client).result.unwrap(), Value::Null);
+    thread::sleep(std::time::Duration::from_millis(600));
+    assert_eq!(hover(&mut client).result.unwrap(), explanation);
+    assert_eq!(requests.try_iter().count(), 1);
+    client.shutdown();
+  }
+
+  #[test]
+  fn prompt_request() {
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "role": "assistant", "content": "  let a = 2;\n" } }]
+    }));
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "OpenAI", "config": { "url": url } },
+        "messages": [{ "role": "user", "content": "{{ prompt }}: {{{ selection }}}" }],
+        "prompts": ["Fix bug", "Simplify"]
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let rewrite =
+      json!({ "command": "famulus-rewrite", "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }] });
+
+    let id = client.send_request("workspace/executeCommand", rewrite.clone());
+    let request = client.server_request("window/showMessageRequest");
+    assert_eq!(
+      request.params["actions"],
+      json!([{ "title": "Fix bug" }, { "title": "Simplify" }])
+    );
+    client.respond(request.id, json!({ "title": "Simplify" }));
+    assert_eq!(client.response(id).result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { URI: [{ "range": range((1, 10), (1, 11)), "newText": "2" }] } })
+    );
+    let body = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(body["messages"][0]["content"], "Simplify:   let a = 1;\n");
+
+    // Dismissing the prompts answers without a rewrite.
+    let id = client.send_request("workspace/executeCommand", rewrite.clone());
+    let request = client.server_request("window/showMessageRequest");
+    client.respond(request.id, Value::Null);
+    assert_eq!(client.response(id).result, Some(Value::Null));
+
+    let id = client.send_request("workspace/executeCommand", rewrite);
+    let request = client.server_request("window/showMessageRequest");
+    client
+      .connection
+      .sender
+      .send(Message::Response(Response::new_err(
+        request.id,
+        ErrorCode::InternalError as i32,
+        "No UI".to_string(),
+      )))
+      .unwrap();
+    let error = client.response(id).error.unwrap();
+    assert_eq!(error.code, ErrorCode::RequestFailed as i32);
+    assert!(error.message.starts_with("Failed to get prompt: "), "{}", error.message);
+    assert!(requests.try_recv().is_err());
+    client.shutdown();
+  }
+
+  #[test]
+  fn progress() {
+    let start = |url: Option<String>| {
+      let model_config = match url {
+        Some(url) => json!({ "provider": "OpenAI", "config": { "url": url } }),
+        None => json!({ "provider": "Mock", "config": { "responses": ["  let a = 2;\n"] } }),
+      };
+      let client = Client::start_with(
+        json!({ "window": { "workDoneProgress": true } }),
+        json!({
+          "rewrite": {
+            "model_config": model_config,
+            "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+          }
+        }),
+      );
+      client.open("fn main() {\n  let a = 1;\n}\n");
+      client
+    };
+    let rewrite =
+      json!({ "command": "famulus-rewrite", "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }, ""] });
+
+    let mut client = start(None);
+    let id = client.send_request("workspace/executeCommand", rewrite.clone());
+    let request = client.server_request("window/workDoneProgress/create");
+    let token = request.params["token"].clone();
+    client.respond(request.id, Value::Null);
+    let mut kinds = Vec::new();
+    let response = loop {
+      match client.receive() {
+        Message::Notification(notification) if notification.method == "$/progress" => {
+          assert_eq!(notification.params["token"], token);
+          kinds.push(notification.params["value"]["kind"].clone());
+        }
+        Message::Response(response) if response.id == id => break response,
+        message => client.received.push_back(message),
+      }
+    };
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(kinds, vec![json!("begin"), json!("end")]);
+    client.apply_edit();
+    client.shutdown();
+
+    // Cancelling the progress cancels the request.
+    let mut client = start(Some(silent_server()));
+    let id = client.send_request("workspace/executeCommand", rewrite);
+    let request = client.server_request("window/workDoneProgress/create");
+    let token = request.params["token"].clone();
+    client.respond(request.id, Value::Null);
+    loop {
+      if let Message::Notification(notification) = client.receive() {
+        if notification.method == "$/progress" {
+          assert_eq!(notification.params["value"]["kind"], "begin");
+          break;
+        }
+      }
+    }
+    client.notify("window/workDoneProgress/cancel", json!({ "token": token }));
+    let response = client.response(id);
+    assert_eq!(response.error.unwrap().code, ErrorCode::RequestCanceled as i32);
+    client.shutdown();
+  }
+
+  #[test]
+  fn command_errors() {
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["  let b = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }]
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.request(
+      "textDocument/codeAction",
+      json!({ "textDocument": { "uri": URI }, "range": range((1, 0), (2, 0)), "context": { "diagnostics": [] } }),
+    );
+    let actions = response.result.unwrap();
+    assert!(!actions
+      .as_array()
+      .unwrap()
+      .iter()
+      .any(|action| action["command"]["command"] == "famulus-rewrite"));
+
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-rewrite", "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }] }),
+    );
+    let error = response.error.unwrap();
+    assert_eq!(error.code, ErrorCode::RequestFailed as i32);
+    assert_eq!(error.message, "No rewrite prompts configured");
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-rewrite", "arguments": [] }),
+    );
+    assert_eq!(response.error.unwrap().code, ErrorCode::RequestFailed as i32);
+
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({
+        "command": "famulus-rewrite-preset",
+        "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }, "Unknown"]
+      }),
+    );
+    let error = response.error.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidParams as i32);
+    assert_eq!(error.message, "Unknown preset: Unknown");
+
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({
+        "command": "famulus-document",
+        "arguments": [{ "textDocument": { "uri": URI }, "position": { "line": 3, "character": 0 } }]
+      }),
+    );
+    let error = response.error.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidParams as i32);
+    assert_eq!(error.message, "No declaration found at line 4");
+
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-explain", "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }] }),
+    );
+    assert_eq!(
+      response.error.unwrap().message,
+      "Failed to get response: The model gave no answer"
+    );
+
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    client.apply_edit();
+    client.shutdown();
+  }
+
+  #[test]
+  fn rewrite_files() {
+    let answer =
+      "```rust \"/project/src/main.rs:2-3\"\n  let a = 3;\n```\n\n```/project/src/main.rs:3-4\n  let b = 4;\n```\n";
+    let mut client = Client::start(json!({
+      "rewrite_files": {
+        "model_config": { "provider": "Mock", "config": { "responses": [answer] } },
+        "messages": [{ "role": "user", "content": "{{ prompt }}" }]
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n  let b = 2;\n}\n");
+    let other = "file:///project/src/lib.rs";
+    client.notify(
+      "textDocument/didOpen",
+      json!({ "textDocument": { "uri": other, "languageId": "rust", "version": 1, "text": "" } }),
+    );
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({
+        "command": "famulus-rewrite-files",
+        "arguments": [
+          [{ "uri": URI, "range": range((1, 0), (2, 0)) }, { "uri": URI, "range": range((2, 0), (3, 0)) }, other],
+          ""
+        ]
+      }),
+    );
+    assert_eq!(response.result, Some(Value::Null));
+    let message = loop {
+      if let Message::Notification(notification) = client.receive() {
+        if notification.method == "window/showMessage" {
+          break notification;
+        }
+      }
+    };
+    assert_eq!(
+      message.params["message"],
+      "The answer contains no code block for /project/src/lib.rs"
+    );
+    assert_eq!(
+      client.apply_edit(),
+      json!({
+        "changes": {
+          URI: [
+            { "range": range((1, 10), (1, 11)), "newText": "3" },
+            { "range": range((2, 10), (2, 11)), "newText": "4" }
+          ]
+        }
+      })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn explain() {
+    let mut client = Client::start_with(
+      json!({ "window": { "showDocument": { "support": true } } }),
+      json!({
+        "explain": {
+          "model_config": { "provider": "Mock", "config": { "responses": ["It declares `a`."] } },
+          "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+          "output": "Document"
+        }
+      }),
+    );
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-explain", "arguments": [{ "uri": URI, "range": range((1, 0), (2, 0)) }] }),
+    );
+    assert_eq!(response.result.unwrap(), "It declares `a`.");
+    let request = client.server_request("window/showDocument");
+    let uri = Url::parse(request.params["uri"].as_str().unwrap()).unwrap();
+    let path = uri.to_file_path().unwrap();
+    assert!(path.to_string_lossy().ends_with(".md"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "It declares `a`.");
+    client.respond(request.id, json!({ "success": true }));
+    client.shutdown();
+    // The temporary documents are removed with the server.
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn generate_tests() {
+    let mut client = Client::start(json!({
+      "generate_tests": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["#[test]\nfn b() {}\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+        "path": "../tests/{stem} test.{ext}"
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let generate = json!({
+      "command": "famulus-generate-tests",
+      "arguments": [{ "uri": URI, "range": range((0, 0), (3, 0)) }]
+    });
+    let response = client.request("workspace/executeCommand", generate.clone());
+    assert_eq!(
+      response.error.unwrap().message,
+      "The client can't create /project/tests/main test.rs"
+    );
+
+    let test_uri = "file:///project/tests/main%20test.rs";
+    client.notify(
+      "textDocument/didOpen",
+      json!({ "textDocument": { "uri": test_uri, "languageId": "rust", "version": 1, "text": "#[test]\nfn a() {}\n" } }),
+    );
+    let response = client.request("workspace/executeCommand", generate);
+    assert!(response.error.is_none(), "{:?}", response.error);
+    assert_eq!(
+      client.apply_edit(),
+      json!({
+        "changes": {
+          test_uri: [{ "range": range((2, 0), (2, 0)), "newText": "\n#[test]\nfn b() {}\n" }]
+        }
+      })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn commit_message() {
+    let folder = env::temp_dir().join(format!("famulus commit {}", process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    fs::write(folder.join("staged.txt"), "staged\n").unwrap();
+    let git = |args: &[&str]| {
+      let status = process::Command::new("git")
+        .args(args)
+        .current_dir(&folder)
+        .status()
+        .unwrap();
+      assert!(status.success());
+    };
+    git(&["init", "-q"]);
+    git(&["add", "staged.txt"]);
+
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "role": "assistant", "content": "Add staged.txt" } }]
+    }));
+    let mut client = Client::start(json!({
+      "commit_message": {
+        "model_config": { "provider": "OpenAI", "config": { "url": url, "model": "gpt-4o-mini" } },
+        "messages": [{ "role": "user", "content": "{{{ diff }}}" }]
+      }
+    }));
+    let uri = Url::from_file_path(folder.join("COMMIT_EDITMSG")).unwrap().to_string();
+    client.notify(
+      "textDocument/didOpen",
+      json!({ "textDocument": { "uri": uri, "languageId": "gitcommit", "version": 1, "text": "" } }),
+    );
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({
+        "command": "famulus-commit-message",
+        "arguments": [{ "textDocument": { "uri": uri }, "position": { "line": 0, "character": 0 } }]
+      }),
+    );
+    fs::remove_dir_all(&folder).unwrap();
+    assert!(response.error.is_none(), "{:?}", response.error);
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    let diff = request["messages"][0]["content"].as_str().unwrap();
+    assert!(diff.contains("+++ b/staged.txt"), "{}", diff);
+    client.apply_edit();
+    client.shutdown();
+  }
+
+  #[test]
+  fn rewrite_files_json() {
+    let answer = json!({ "files": [{ "tag": "/project/src/main.rs", "content": "fn main() {\n  let a = 2;\n}\n" }] });
+    let mut client = Client::start(json!({
+      "rewrite_files": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["{}", answer.to_string()] } },
+        "messages": [{ "role": "user", "content": "{{ prompt }}" }],
+        "response_format": {
+          "type": "JsonSchema",
+          "name": "files",
+          "schema": { "type": "object", "required": ["files"] }
+        }
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-rewrite-files", "arguments": [[URI], ""] }),
+    );
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { URI: [{ "range": range((1, 10), (1, 11)), "newText": "2" }] } })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn infill_providers() {
+    /// Completes the middle of a document with `infill`, returning the completion and the request body.
+    fn complete(infill: Value, requests: std::sync::mpsc::Receiver<Value>) -> (Value, Value) {
+      let mut client = Client::start(json!({ "infill": infill }));
+      client.open("fn main() {\n  \n}\n");
+      let id = client.inline_completion(1, 2);
+      let response = client.response(id);
+      client.shutdown();
+      (response.result.unwrap(), requests.recv_timeout(TIMEOUT).unwrap())
+    }
+    let expected = json!([{ "insertText": "println!();", "range": range((1, 2), (1, 2)) }]);
+
+    // The stub doesn't check the key, so any variable set before the tests start will do.
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "content": "println!();" } }],
+      "usage": { "prompt_tokens": 3, "completion_tokens": 1 }
+    }));
+    let infill = json!({
+      "provider": "Mistral",
+      "config": { "url": url, "api_key_env": "CARGO_PKG_NAME", "model": "codestral-latest", "seed": 42 }
+    });
+    let (result, request) = complete(infill, requests);
+    assert_eq!(result, expected);
+    assert_eq!(
+      request,
+      json!({
+        "model": "codestral-latest",
+        "prompt": "fn main() {\n  ",
+        "suffix": "\n}\n",
+        "random_seed": 42
+      })
+    );
+
+    let (url, requests) = stub_server(json!({ "response": "println!();", "prompt_eval_count": 3, "eval_count": 1 }));
+    let infill = json!({
+      "provider": "Ollama",
+      "config": { "url": url, "model": "qwen2.5-coder", "max_tokens": 64 }
+    });
+    let (result, request) = complete(infill, requests);
+    assert_eq!(result, expected);
+    assert_eq!(
+      request,
+      json!({
+        "model": "qwen2.5-coder",
+        "prompt": "fn main() {\n  ",
+        "suffix": "\n}\n",
+        "stream": false,
+        "options": { "num_predict": 64 }
+      })
+    );
+
+    let (url, requests) = stub_server(json!({ "choices": [{ "text": "println!();" }] }));
+    let infill = json!({
+      "provider": "OpenAICompletions",
+      "config": { "url": url, "model": "qwen2.5-coder", "stop": ["\n\n"] },
+      "template": "<|fim_prefix|>{{{ prefix }}}<|fim_suffix|>{{{ suffix }}}<|fim_middle|>"
+    });
+    let (result, request) = complete(infill, requests);
+    assert_eq!(result, expected);
+    assert_eq!(
+      request,
+      json!({
+        "model": "qwen2.5-coder",
+        "prompt": "<|fim_prefix|>fn main() {\n  <|fim_suffix|>\n}\n<|fim_middle|>",
+        "stop": ["\n\n"]
+      })
+    );
+  }
+
+  #[test]
+  fn stream() {
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["", "  let b = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+        "stream": true
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    // An empty answer leaves the selection alone.
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({
+        "changes": {
+          URI: [
+            { "range": range((1, 6), (1, 7)), "newText": "b" },
+            { "range": range((1, 10), (1, 11)), "newText": "2" }
+          ]
+        }
+      })
+    );
+    client.shutdown();
+  }
+
+  fn preview_options() -> Value {
+    json!({
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": ["  let a = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+        "preview": true
+      }
+    })
+  }
+
+  #[test]
+  fn preview() {
+    let mut client = Client::start_with(
+      json!({ "window": { "showDocument": { "support": true } } }),
+      preview_options(),
+    );
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    let request = client.server_request("window/showDocument");
+    let uri = Url::parse(request.params["uri"].as_str().unwrap()).unwrap();
+    let path = uri.to_file_path().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {\n  let a = 2;\n}\n");
+    client.respond(request.id, json!({ "success": true }));
+    let request = client.server_request("window/showMessageRequest");
+    assert_eq!(request.params["message"], "Apply the rewrite?");
+    // A change above the selection while confirming moves the edit.
+    client.change(2, range((0, 0), (0, 0)), "// a\n");
+    client.respond(request.id, json!({ "title": "Apply" }));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { URI: [{ "range": range((2, 10), (2, 11)), "newText": "2" }] } })
+    );
+    assert!(!path.exists());
+
+    // A change to the selection discards the rewrite.
+    let response = client.rewrite(range((2, 0), (3, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    let request = client.server_request("window/showDocument");
+    client.respond(request.id, json!({ "success": true }));
+    let request = client.server_request("window/showMessageRequest");
+    client.change(3, range((2, 2), (2, 5)), "const");
+    client.respond(request.id, json!({ "title": "Apply" }));
+    let message = loop {
+      if let Message::Notification(notification) = client.receive() {
+        if notification.method == "window/showMessage" {
+          break notification;
+        }
+      }
+    };
+    assert_eq!(
+      message.params["message"],
+      "Rewrite discarded: the selection was changed while the request was running"
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn preview_annotations() {
+    let mut client = Client::start_with(
+      json!({ "workspace": { "workspaceEdit": { "documentChanges": true, "changeAnnotationSupport": {} } } }),
+      preview_options(),
+    );
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    let edit = client.apply_edit();
+    assert_eq!(
+      edit["documentChanges"],
+      json!([{
+        "textDocument": { "uri": URI, "version": 1 },
+        "edits": [{ "range": range((1, 10), (1, 11)), "newText": "2", "annotationId": "famulus-rewrite" }]
+      }])
+    );
+    assert_eq!(edit["changeAnnotations"]["famulus-rewrite"]["needsConfirmation"], true);
+    client.shutdown();
+  }
+
+  #[test]
+  fn preview_message() {
+    let mut client = Client::start(preview_options());
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    // Without `window/showDocument`, the question shows the rewritten selection.
+    let request = client.server_request("window/showMessageRequest");
+    assert_eq!(request.params["message"], "Apply the rewrite?\n\n  let a = 2;\n");
+    client.respond(request.id, json!({ "title": "Discard" }));
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    let request = client.server_request("window/showMessageRequest");
+    client.respond(request.id, json!({ "title": "Apply" }));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { URI: [{ "range": range((1, 10), (1, 11)), "newText": "2" }] } })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn tools() {
+    let read = json!({ "tool_calls": [{ "name": "read_file", "arguments": { "path": "src/lib.rs" } }] });
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "Mock", "config": { "responses": [read, "  let a = 2;\n"] } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+        "tools": true
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(response.result, Some(Value::Null));
+    assert_eq!(
+      client.apply_edit(),
+      json!({ "changes": { URI: [{ "range": range((1, 10), (1, 11)), "newText": "2" }] } })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn tool_rounds() {
+    let (url, requests) = stub_server(json!({
+      "choices": [{
+        "message": {
+          "role": "assistant",
+          "content": "Reading",
+          "tool_calls": [{
+            "id": "call-0",
+            "type": "function",
+            "function": { "name": "read_file", "arguments": "{\"path\":\"src/lib.rs\"}" }
+          }]
+        }
+      }]
+    }));
+    let mut client = Client::start(json!({
+      "rewrite": {
+        "model_config": { "provider": "OpenAI", "config": { "url": url } },
+        "messages": [{ "role": "user", "content": "{{{ selection }}}" }],
+        "tools": true
+      }
+    }));
+    client.open("fn main() {\n  let a = 1;\n}\n");
+    let response = client.rewrite(range((1, 0), (2, 0)), "");
+    assert_eq!(
+      response.error.unwrap().message,
+      format!(
+        "Failed to get response: No answer after {} rounds of tool calls",
+        MAX_TOOL_ROUNDS
+      )
+    );
+    let bodies = requests.try_iter().collect::<Vec<_>>();
+    assert_eq!(bodies.len(), MAX_TOOL_ROUNDS);
+    // The text answered with the tool calls is sent back with them.
+    let assistant = &bodies[1]["messages"][1];
+    assert_eq!(assistant["role"], "assistant");
+    assert_eq!(assistant["content"], "Reading");
+    assert_eq!(assistant["tool_calls"][0]["id"], "call-0");
+    assert_eq!(bodies[1]["messages"][2]["tool_call_id"], "call-0");
+    client.shutdown();
+  }
+
+  #[test]
+  fn usage() {
+    let (url, _requests) = stub_server(json!({
+      "content": "println!();",
+      "model": "qwen",
+      "timings": { "prompt_n": 300000, "predicted_n": 100000 }
+    }));
+    let mut client = Client::start(json!({
+      "infill": { "provider": "LlamaCpp", "config": { "url": url } },
+      "usage": { "prices": { "qwen": { "prompt": 1.0, "completion": 2.0 } } }
+    }));
+    client.open("fn main() {\n}\n");
+    let id = client.inline_completion(1, 0);
+    assert!(client.response(id).error.is_none());
+    let response = client.request(
+      "workspace/executeCommand",
+      json!({ "command": "famulus-usage", "arguments": [] }),
+    );
+    assert_eq!(
+      response.result.unwrap(),
+      json!({
+        "models": [{
+          "model": "qwen",
+          "requests": 1,
+          "prompt_tokens": 300000,
+          "completion_tokens": 100000,
+          "cost": 0.5
+        }],
+        "cost": 0.5
+      })
+    );
+    client.shutdown();
+  }
+
+  #[test]
+  fn review() {
+    let comments = |line: u32| json!([{ "line": line, "message": "Unused" }]).to_string();
+    let mut client = Client::start(json!({
+      "review": {
+        "enabled": true,
+        "model_config": { "provider": "Mock", "config": { "responses": [comments(2), comments(3)] } },
+        "messages": [{ "role": "user", "content": "{{{ diff }}}" }]
+      }
+    }));
+    client.open("fn main() {\n}\n");
+    let diagnostics = |client: &mut Client| loop {
+      if let Message::Notification(notification) = client.receive() {
+        if notification.method == "textDocument/publishDiagnostics" {
+          break notification.params["diagnostics"].clone();
+        }
+      }
+    };
+    for (version, line) in [(2, 2), (3, 3)] {
+      client.change(version, range((1, 0), (1, 0)), "  let a = 1;\n");
+      client.notify("textDocument/didSave", json!({ "textDocument": { "uri": URI } }));
+      assert_eq!(diagnostics(&mut client)[0]["range"]["start"]["line"], line - 1);
+    }
+    client.shutdown();
+  }
+
+  #[test]
+  fn review_fix() {
+    let comments = json!([{ "line": 2, "message": "Off by one", "replacement": "  let a = 0;" }]).to_string();
+    let mut client = Client::start(json!({
+      "review": {
+        "enabled": true,
+        "model_config": { "provider": "Mock", "config": { "responses": [comments] } },
+        "messages": [{ "role": "user", "content": "{{{ diff }}}" }]
+      }
+    }));
+    client.open("fn main() {\n}\n");
+    client.change(2, range((1, 0), (1, 0)), "  let a = 1;\n");
+    client.notify("textDocument/didSave", json!({ "textDocument": { "uri": URI } }));
+    let diagnostics = loop {
+      if let Message::Notification(notification) = client.receive() {
+        if notification.method == "textDocument/publishDiagnostics" {
+          break notification.params["diagnostics"].clone();
+        }
+      }
+    };
+    // The fix follows its line when the lines above it change after the review.
+    client.change(3, range((0, 0), (0, 0)), "// Entry point.\n");
+    let response = client.request(
+      "textDocument/codeAction",
+      json!({ "textDocument": { "uri": URI }, "range": range((2, 0), (2, 0)), "context": { "diagnostics": diagnostics } }),
+    );
+    let actions = response.result.unwrap();
+    assert_eq!(
+      actions[0]["edit"],
+      json!({ "changes": { URI: [{ "range": range((2, 0), (2, 12)), "newText": "  let a = 0;" }] } })
+    );
+    // A fix for a line that was changed since is not offered.
+    client.change(4, range((2, 10), (2, 11)), "2");
+    let response = client.request(
+      "textDocument/codeAction",
+      json!({ "textDocument": { "uri": URI }, "range": range((2, 0), (2, 0)), "context": { "diagnostics": diagnostics } }),
+    );
+    assert_eq!(response.result.unwrap(), json!([]));
+    client.shutdown();
+  }
+
+  #[test]
+  fn sessions() {
+    let mut client = Client::start(json!({ "session": { "session_limit": 2 } }));
+    let mut command = |command: &str, arguments: Value| {
+      client.request(
+        "workspace/executeCommand",
+        json!({ "command": command, "arguments": arguments }),
+      )
+    };
+    let a = command("famulus-session-start", json!([])).result.unwrap();
+    assert_eq!(a, "famulus-session-0");
+    let b = command("famulus-session-start", json!([])).result.unwrap();
+    assert!(command("famulus-session-reset", json!([a])).error.is_none());
+    let c = command("famulus-session-start", json!([])).result.unwrap();
+    assert!(command("famulus-session-reset", json!([a])).error.is_none());
+    assert!(command("famulus-session-reset", json!([c])).error.is_none());
+    let error = command("famulus-session-reset", json!([b])).error.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidParams as i32);
+    // Without a model there is no answer to record.
+    let error = command("famulus-session-send", json!([c, "Hi"])).error.unwrap();
+    assert_eq!(error.message, "Failed to get response: The model gave no answer");
+    client.shutdown();
+  }
 }
@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
   config::{Mistral, ModelConfig},
   infill::Infill,
+  trace,
+  usage::{self, TokenUsage},
 };
 
 #[derive(Clone, PartialEq, Debug, Serialize)]
@@ -42,14 +44,16 @@ struct Choice {
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 struct InfillResponse {
   choices: Vec<Choice>,
+  #[serde(default)]
+  usage: Option<TokenUsage>,
 }
 
 impl Infill for ModelConfig<Mistral> {
   async fn infill(&self, client: Arc<Client>, prefix: String, suffix: String) -> Result<impl Iterator<Item = String>> {
-    let response = client
-      .post(&self.url)
-      .bearer_auth(&env::var(&self.api_key_env)?)
-      .json(&InfillRequest {
+    let request = client.post(&self.url).bearer_auth(&env::var(&self.api_key_env)?);
+    let response: InfillResponse = trace::post(
+      request,
+      &InfillRequest {
         model: &self.generation_config.model,
         prompt: prefix,
         suffix: Some(suffix),
@@ -59,11 +63,15 @@ impl Infill for ModelConfig<Mistral> {
         min_tokens: self.generation_config.min_tokens,
         stop: &self.generation_config.stop,
         random_seed: self.generation_config.seed,
-      })
-      .send()
-      .await?
-      .json::<InfillResponse>()
-      .await?;
+      },
+    )
+    .await?;
+    if let Some(usage) = response.usage {
+      usage::record(
+        usage::model_name(Some(&self.generation_config.model), None, &self.url),
+        usage.into(),
+      );
+    }
 
     Ok(response.choices.into_iter().map(|choice| choice.message.content))
   }
@@ -0,0 +1,239 @@
+use std::{
+  fs, iter,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, OnceLock,
+  },
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::{
+  chat::{Chat, ChatMessage, ChatReply, ToolCall, ToolDefinition},
+  config::{MockConfig, MockResponse, ReplayConfig, ResponseFormatConfig},
+  infill::Infill,
+};
+
+/// Paths of the answer in the responses of the supported wire formats.
+const ANSWER_POINTERS: &[&str] = &["/choices/0/message/content", "/choices/0/text", "/content", "/response"];
+
+#[derive(Debug)]
+enum Answers {
+  Mock(Arc<MockConfig>),
+  Replay {
+    config: Arc<ReplayConfig>,
+    /// The recorded answers, read on first use.
+    answers: OnceLock<Result<Vec<String>, String>>,
+  },
+}
+
+/// A provider answering without network requests, for tests.
+#[derive(Debug)]
+pub struct Script {
+  answers: Answers,
+  next: AtomicUsize,
+}
+
+/// The scripts of a server, so that each configured provider keeps its position across requests.
+#[derive(Debug, Default)]
+pub struct Scripts(DashMap<usize, Arc<Script>>);
+
+impl Scripts {
+  fn get(&self, key: usize, answers: impl FnOnce() -> Answers) -> Arc<Script> {
+    self
+      .0
+      .entry(key)
+      .or_insert_with(|| {
+        Arc::new(Script {
+          answers: answers(),
+          next: AtomicUsize::new(0),
+        })
+      })
+      .clone()
+  }
+
+  pub fn mock(&self, config: &Arc<MockConfig>) -> Arc<Script> {
+    self.get(config.id, || Answers::Mock(config.clone()))
+  }
+
+  pub fn replay(&self, config: &Arc<ReplayConfig>) -> Arc<Script> {
+    self.get(config.id, || Answers::Replay {
+      config: config.clone(),
+      answers: OnceLock::new(),
+    })
+  }
+}
+
+fn answer(response: &Value) -> Option<String> {
+  ANSWER_POINTERS
+    .iter()
+    .find_map(|pointer| response.pointer(pointer).and_then(|answer| answer.as_str()))
+    .map(|answer| answer.to_string())
+}
+
+fn read_answers(config: &ReplayConfig) -> Result<Vec<String>> {
+  fs::read_to_string(&config.path)?
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .enumerate()
+    .map(|(index, line)| {
+      let entry: Value = serde_json::from_str(line)?;
+      answer(&entry["response"]).ok_or_else(|| anyhow!("No answer in recorded response {}", index + 1))
+    })
+    .collect()
+}
+
+impl Script {
+  fn next_reply(&self) -> Result<ChatReply> {
+    let index = self.next.fetch_add(1, Ordering::Relaxed);
+    match &self.answers {
+      Answers::Mock(config) => {
+        let response = config
+          .responses
+          .get(index)
+          .or(config.responses.last())
+          .ok_or_else(|| anyhow!("No mock responses"))?;
+        Ok(match response {
+          MockResponse::Text(text) => ChatReply {
+            content: Some(text.clone()),
+            tool_calls: Vec::new(),
+          },
+          MockResponse::ToolCalls { content, tool_calls } => ChatReply {
+            content: content.clone(),
+            tool_calls: tool_calls
+              .iter()
+              .enumerate()
+              .map(|(call, tool_call)| ToolCall {
+                id: format!("call-{}-{}", index, call),
+                name: tool_call.name.clone(),
+                arguments: tool_call.arguments.to_string(),
+              })
+              .collect(),
+          },
+        })
+      }
+      Answers::Replay { config, answers } => {
+        let answers = answers
+          .get_or_init(|| read_answers(config).map_err(|error| error.to_string()))
+          .as_ref()
+          .map_err(|error| anyhow!("Failed to read {}: {}", config.path.display(), error))?;
+        let answer = answers
+          .get(index)
+          .cloned()
+          .ok_or_else(|| anyhow!("No more recorded answers in {}", config.path.display()))?;
+        Ok(ChatReply {
+          content: Some(answer),
+          tool_calls: Vec::new(),
+        })
+      }
+    }
+  }
+
+  fn next(&self) -> Result<String> {
+    let reply = self.next_reply()?;
+    if !reply.tool_calls.is_empty() {
+      return Err(anyhow!("Tool calls can't be answered without tools"));
+    }
+    Ok(reply.content.unwrap_or_default())
+  }
+}
+
+impl Infill for Script {
+  async fn infill(
+    &self,
+    _client: Arc<Client>,
+    _prefix: String,
+    _suffix: String,
+  ) -> Result<impl Iterator<Item = String>> {
+    Ok(iter::once(self.next()?))
+  }
+}
+
+impl Chat for Script {
+  async fn chat(&self, _client: Arc<Client>, _messages: Vec<(String, String)>) -> Result<impl Iterator<Item = String>> {
+    Ok(iter::once(self.next()?))
+  }
+
+  async fn chat_with_format(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<(String, String)>,
+    _response_format: ResponseFormatConfig,
+  ) -> Result<impl Iterator<Item = String>> {
+    Ok(iter::once(self.next()?))
+  }
+
+  async fn chat_stream(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<(String, String)>,
+    mut on_delta: impl FnMut(&str) + Send,
+  ) -> Result<Option<String>> {
+    let answer = self.next()?;
+    answer.split_inclusive('\n').for_each(&mut on_delta);
+    Ok(Some(answer))
+  }
+
+  async fn chat_with_tools(
+    &self,
+    _client: Arc<Client>,
+    _messages: Vec<ChatMessage>,
+    _tools: Vec<ToolDefinition>,
+  ) -> Result<ChatReply> {
+    self.next_reply()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, sync::Arc};
+
+  use serde_json::json;
+
+  use crate::config::{MockConfig, ReplayConfig};
+
+  use super::Scripts;
+
+  #[test]
+  fn mock() {
+    let scripts = Scripts::default();
+    let config: Arc<MockConfig> = Arc::new(
+      serde_json::from_value(json!({
+        "responses": ["a", { "content": "b", "tool_calls": [{ "name": "read_file", "arguments": { "path": "a.rs" } }] }]
+      }))
+      .unwrap(),
+    );
+    assert_eq!(scripts.mock(&config).next().unwrap(), "a");
+    let reply = scripts.mock(&config).next_reply().unwrap();
+    assert_eq!(reply.content.as_deref(), Some("b"));
+    assert_eq!(reply.tool_calls[0].name, "read_file");
+    assert_eq!(reply.tool_calls[0].arguments, r#"{"path":"a.rs"}"#);
+    assert!(scripts.mock(&config).next().is_err());
+    assert_eq!(Scripts::default().mock(&config).next().unwrap(), "a");
+  }
+
+  #[test]
+  fn replay() {
+    let path = env::temp_dir().join(format!("famulus-replay-{}.jsonl", std::process::id()));
+    fs::write(
+      &path,
+      r#"{"url":"http://localhost/infill","request":{},"response":{"content":"a"}}
+{"url":"http://localhost/v1/chat/completions","request":{},"response":{"choices":[{"message":{"role":"assistant","content":"b"}}]}}
+{"url":"http://localhost/api/generate","request":{},"response":{"response":"c"}}
+"#,
+    )
+    .unwrap();
+    let script = Scripts::default().replay(&Arc::new(ReplayConfig {
+      id: 0,
+      path: path.clone(),
+    }));
+    assert_eq!(script.next().unwrap(), "a");
+    fs::remove_file(path).unwrap();
+    assert_eq!(script.next().unwrap(), "b");
+    assert_eq!(script.next().unwrap(), "c");
+    assert!(script.next().is_err());
+  }
+}
@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
   config::{ModelConfig, Ollama},
   infill::Infill,
+  trace,
+  usage::{self, Usage},
 };
 
 #[derive(Clone, PartialEq, Debug, Serialize)]
@@ -33,6 +35,10 @@ struct GenerateRequest<'a> {
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 struct GenerateResponse {
   response: String,
+  #[serde(default)]
+  prompt_eval_count: u64,
+  #[serde(default)]
+  eval_count: u64,
 }
 
 impl Infill for ModelConfig<Ollama> {
@@ -43,8 +49,9 @@ impl Infill for ModelConfig<Ollama> {
     } else {
       request
     };
-    let response = request
-      .json(&GenerateRequest {
+    let response: GenerateResponse = trace::post(
+      request,
+      &GenerateRequest {
         model: &self.generation_config.model,
         prompt: prefix,
         suffix,
@@ -55,11 +62,13 @@ impl Infill for ModelConfig<Ollama> {
           num_predict: self.generation_config.max_tokens,
           seed: self.generation_config.seed,
         },
-      })
-      .send()
-      .await?
-      .json::<GenerateResponse>()
-      .await?;
+      },
+    )
+    .await?;
+    usage::record(
+      usage::model_name(Some(&self.generation_config.model), None, &self.url),
+      Usage::new(response.prompt_eval_count, response.eval_count),
+    );
     Ok(iter::once(response.response))
   }
 }
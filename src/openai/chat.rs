@@ -1,24 +1,109 @@
 use std::{env, sync::Arc};
 
-use anyhow::Result;
-use reqwest::Client;
+use anyhow::{anyhow, Result};
+use reqwest::{Client, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{
-  chat::Chat,
-  config::{ModelConfig, OpenAI},
+  chat::{Chat, ChatMessage, ChatReply, PartialAnswer, ToolCall, ToolDefinition},
+  config::{ModelConfig, OpenAI, ResponseFormatConfig},
+  trace,
+  usage::{self, TokenUsage},
 };
 
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct OpenAIFunctionCall {
+  name: String,
+  arguments: String,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct OpenAIToolCall {
+  id: String,
+  #[serde(rename = "type")]
+  typ: String,
+  function: OpenAIFunctionCall,
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 struct OpenAIChatMessage {
   role: String,
-  content: String,
+  #[serde(default)]
+  content: Option<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  tool_calls: Vec<OpenAIToolCall>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  tool_call_id: Option<String>,
+}
+
+impl OpenAIChatMessage {
+  fn new(role: String, content: String) -> Self {
+    OpenAIChatMessage {
+      role,
+      content: Some(content),
+      tool_calls: Vec::new(),
+      tool_call_id: None,
+    }
+  }
+}
+
+impl From<ChatMessage> for OpenAIChatMessage {
+  fn from(message: ChatMessage) -> Self {
+    match message {
+      ChatMessage::Message { role, content } => OpenAIChatMessage::new(role, content),
+      ChatMessage::ToolCalls { content, tool_calls } => OpenAIChatMessage {
+        role: "assistant".to_string(),
+        content,
+        tool_calls: tool_calls
+          .into_iter()
+          .map(|tool_call| OpenAIToolCall {
+            id: tool_call.id,
+            typ: "function".to_string(),
+            function: OpenAIFunctionCall {
+              name: tool_call.name,
+              arguments: tool_call.arguments,
+            },
+          })
+          .collect(),
+        tool_call_id: None,
+      },
+      ChatMessage::ToolResult { id, content } => OpenAIChatMessage {
+        role: "tool".to_string(),
+        content: Some(content),
+        tool_calls: Vec::new(),
+        tool_call_id: Some(id),
+      },
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+struct OpenAIFunction {
+  name: String,
+  description: String,
+  parameters: Value,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+struct OpenAITool {
+  #[serde(rename = "type")]
+  typ: String,
+  function: OpenAIFunction,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize)]
 struct OpanAIChatRequest<'a> {
   model: &'a Option<String>,
   messages: Vec<OpenAIChatMessage>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  tools: Vec<OpenAITool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  response_format: Option<Value>,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stream_options: Option<Value>,
   #[serde(skip_serializing_if = "Option::is_none")]
   temperature: Option<f64>,
   #[serde(skip_serializing_if = "Option::is_none")]
@@ -39,34 +124,365 @@ struct OpenAIChatChoice {
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 struct OpenAIChatResponse {
   choices: Vec<OpenAIChatChoice>,
+  #[serde(default)]
+  model: Option<String>,
+  #[serde(default)]
+  usage: Option<TokenUsage>,
 }
 
-impl Chat for ModelConfig<OpenAI> {
-  async fn chat(&self, client: Arc<Client>, messages: Vec<(String, String)>) -> Result<impl Iterator<Item = String>> {
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct OpenAIChatDelta {
+  #[serde(default)]
+  content: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct OpenAIChatStreamChoice {
+  delta: OpenAIChatDelta,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct OpenAIChatStreamResponse {
+  choices: Vec<OpenAIChatStreamChoice>,
+  #[serde(default)]
+  model: Option<String>,
+  #[serde(default)]
+  usage: Option<TokenUsage>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIChatStreamEvent {
+  Error { error: Value },
+  Response(OpenAIChatStreamResponse),
+}
+
+/// Splits server-sent events into the payloads of their `data` fields.
+#[derive(Default)]
+struct EventReader {
+  buffer: Vec<u8>,
+}
+
+impl EventReader {
+  fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+    self.buffer.extend_from_slice(chunk);
+    let mut data = Vec::new();
+    while let Some(index) = self.buffer.iter().position(|&byte| byte == b'\n') {
+      let line = self.buffer.drain(..=index).collect::<Vec<_>>();
+      let line = String::from_utf8_lossy(&line);
+      if let Some(value) = line.trim_end_matches(['\r', '\n']).strip_prefix("data:") {
+        data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+      }
+    }
+    data
+  }
+}
+
+impl ModelConfig<OpenAI> {
+  fn request<'a>(
+    &'a self,
+    client: Arc<Client>,
+    messages: Vec<OpenAIChatMessage>,
+    tools: Vec<OpenAITool>,
+    response_format: Option<Value>,
+    stream: bool,
+  ) -> Result<(RequestBuilder, OpanAIChatRequest<'a>)> {
     let request = client.post(&self.url);
     let request = if let Some(ref api_key_env) = self.api_key_env {
       request.bearer_auth(&env::var(api_key_env)?)
     } else {
       request
     };
-    let response = request
-      .json(&OpanAIChatRequest {
-        model: &self.generation_config.model,
-        messages: messages
+    let body = OpanAIChatRequest {
+      model: &self.generation_config.model,
+      messages,
+      tools,
+      response_format,
+      stream,
+      stream_options: stream.then(|| json!({ "include_usage": true })),
+      temperature: self.generation_config.temperature,
+      top_p: self.generation_config.top_p,
+      max_tokens: self.generation_config.max_tokens,
+      stop: &self.generation_config.stop,
+      seed: self.generation_config.seed,
+    };
+    Ok((request, body))
+  }
+
+  async fn chat_request(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<OpenAIChatMessage>,
+    tools: Vec<OpenAITool>,
+    response_format: Option<Value>,
+  ) -> Result<OpenAIChatResponse> {
+    let (request, body) = self.request(client, messages, tools, response_format, false)?;
+    let response: OpenAIChatResponse = trace::post(request, &body).await?;
+    if let Some(usage) = response.usage {
+      usage::record(
+        usage::model_name(
+          self.generation_config.model.as_deref(),
+          response.model.as_deref(),
+          &self.url,
+        ),
+        usage.into(),
+      );
+    }
+    Ok(response)
+  }
+
+  /// Reads the streamed events of `response` into `answer` until the end of the stream.
+  async fn read_stream(
+    &self,
+    mut response: Response,
+    answer: &mut String,
+    on_delta: &mut (impl FnMut(&str) + Send),
+  ) -> Result<()> {
+    let status = response.status();
+    if !status.is_success() {
+      return Err(anyhow!("HTTP status {}: {}", status, response.text().await?));
+    }
+    let mut reader = EventReader::default();
+    while let Some(chunk) = response.chunk().await? {
+      for data in reader.push(&chunk) {
+        if data == "[DONE]" {
+          return Ok(());
+        }
+        let event = match serde_json::from_str::<OpenAIChatStreamEvent>(&data)? {
+          OpenAIChatStreamEvent::Error { error } => {
+            let message = error["message"]
+              .as_str()
+              .map_or_else(|| error.to_string(), str::to_string);
+            return Err(anyhow!("Stream error: {}", message));
+          }
+          OpenAIChatStreamEvent::Response(event) => event,
+        };
+        if let Some(usage) = event.usage {
+          usage::record(
+            usage::model_name(
+              self.generation_config.model.as_deref(),
+              event.model.as_deref(),
+              &self.url,
+            ),
+            usage.into(),
+          );
+        }
+        let delta = event
+          .choices
           .into_iter()
-          .map(|(role, content)| OpenAIChatMessage { role, content })
-          .collect(),
-        temperature: self.generation_config.temperature,
-        top_p: self.generation_config.top_p,
-        max_tokens: self.generation_config.max_tokens,
-        stop: &self.generation_config.stop,
-        seed: self.generation_config.seed,
-      })
-      .send()
-      .await?
-      .json::<OpenAIChatResponse>()
+          .next()
+          .and_then(|choice| choice.delta.content)
+          .unwrap_or_default();
+        if !delta.is_empty() {
+          on_delta(&delta);
+          answer.push_str(&delta);
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Chat for ModelConfig<OpenAI> {
+  async fn chat(&self, client: Arc<Client>, messages: Vec<(String, String)>) -> Result<impl Iterator<Item = String>> {
+    let messages = messages
+      .into_iter()
+      .map(|(role, content)| OpenAIChatMessage::new(role, content))
+      .collect();
+    let response = self.chat_request(client, messages, Vec::new(), None).await?;
+
+    Ok(
+      response
+        .choices
+        .into_iter()
+        .map(|choice| choice.message.content.unwrap_or_default()),
+    )
+  }
+
+  async fn chat_with_format(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    response_format: ResponseFormatConfig,
+  ) -> Result<impl Iterator<Item = String>> {
+    let messages = messages
+      .into_iter()
+      .map(|(role, content)| OpenAIChatMessage::new(role, content))
+      .collect();
+    let response_format = match response_format {
+      ResponseFormatConfig::JsonObject => json!({ "type": "json_object" }),
+      ResponseFormatConfig::JsonSchema { name, schema, strict } => json!({
+        "type": "json_schema",
+        "json_schema": { "name": name, "schema": schema, "strict": strict }
+      }),
+    };
+    let response = self
+      .chat_request(client, messages, Vec::new(), Some(response_format))
       .await?;
 
-    Ok(response.choices.into_iter().map(|choice| choice.message.content))
+    Ok(
+      response
+        .choices
+        .into_iter()
+        .map(|choice| choice.message.content.unwrap_or_default()),
+    )
+  }
+
+  async fn chat_stream(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<(String, String)>,
+    mut on_delta: impl FnMut(&str) + Send,
+  ) -> Result<Option<String>> {
+    let messages = messages
+      .into_iter()
+      .map(|(role, content)| OpenAIChatMessage::new(role, content))
+      .collect();
+    let (request, body) = self.request(client, messages, Vec::new(), None, true)?;
+    let (response, trace) = trace::send(request, &body).await?;
+    let mut answer = String::new();
+    let result = self.read_stream(response, &mut answer, &mut on_delta).await;
+    match result {
+      Ok(()) => {
+        trace.finish(json!({ "content": answer }));
+        Ok(Some(answer))
+      }
+      Err(error) => {
+        trace.finish(json!({ "content": answer, "error": error.to_string() }));
+        if answer.is_empty() {
+          Err(error)
+        } else {
+          Err(PartialAnswer { answer, error }.into())
+        }
+      }
+    }
+  }
+
+  async fn chat_with_tools(
+    &self,
+    client: Arc<Client>,
+    messages: Vec<ChatMessage>,
+    tools: Vec<ToolDefinition>,
+  ) -> Result<ChatReply> {
+    let messages = messages.into_iter().map(OpenAIChatMessage::from).collect();
+    let tools = tools
+      .into_iter()
+      .map(|tool| OpenAITool {
+        typ: "function".to_string(),
+        function: OpenAIFunction {
+          name: tool.name,
+          description: tool.description,
+          parameters: tool.parameters,
+        },
+      })
+      .collect();
+    let response = self.chat_request(client, messages, tools, None).await?;
+
+    Ok(
+      response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| ChatReply {
+          content: choice.message.content,
+          tool_calls: choice
+            .message
+            .tool_calls
+            .into_iter()
+            .map(|tool_call| ToolCall {
+              id: tool_call.id,
+              name: tool_call.function.name,
+              arguments: tool_call.function.arguments,
+            })
+            .collect(),
+        })
+        .unwrap_or_default(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::{
+    chat::{Chat, PartialAnswer},
+    config::ChatModelConfig,
+    mock::Scripts,
+    stub::{raw_server, stub_server, TIMEOUT},
+  };
+
+  use super::EventReader;
+
+  #[test]
+  fn events() {
+    let mut reader = EventReader::default();
+    assert_eq!(reader.push(b": keep-alive\n\ndata: {\"a\""), Vec::<String>::new());
+    assert_eq!(
+      reader.push(b":1}\r\n\ndata:[DONE]\n"),
+      vec!["{\"a\":1}".to_string(), "[DONE]".to_string()]
+    );
+  }
+
+  #[tokio::test]
+  async fn chat() {
+    let (url, requests) = stub_server(json!({
+      "choices": [{ "message": { "role": "assistant", "content": "  2\n" } }]
+    }));
+    let config: ChatModelConfig =
+      serde_json::from_value(json!({ "provider": "OpenAI", "config": { "url": url, "model": "gpt-4o-mini" } }))
+        .unwrap();
+    let client = Arc::new(reqwest::Client::new());
+    let chat = config.get_chat(&Scripts::default());
+    let mut choices = chat
+      .chat(client, vec![("user".to_string(), "Increment:   1\n".to_string())])
+      .await
+      .unwrap();
+    assert_eq!(choices.next().unwrap(), "  2\n");
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request["model"], "gpt-4o-mini");
+    assert_eq!(
+      request["messages"],
+      json!([{ "role": "user", "content": "Increment:   1\n" }])
+    );
+  }
+
+  async fn stream(response: &str) -> anyhow::Result<Option<String>> {
+    let (url, _requests) = raw_server(format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: 1000\r\nConnection: close\r\n\r\n{}",
+      response
+    ));
+    let config: ChatModelConfig =
+      serde_json::from_value(json!({ "provider": "OpenAI", "config": { "url": url } })).unwrap();
+    let client = Arc::new(reqwest::Client::new());
+    let chat = config.get_chat(&Scripts::default());
+    chat
+      .chat_stream(client, vec![("user".to_string(), "Count".to_string())], |_| {})
+      .await
+  }
+
+  #[tokio::test]
+  async fn chat_stream_errors() {
+    let delta = "data: {\"choices\":[{\"delta\":{\"content\":\"1 2\"}}]}\n\n";
+    let error = stream("data: {\"error\":{\"message\":\"Overloaded\"}}\n\n")
+      .await
+      .unwrap_err();
+    assert_eq!(error.to_string(), "Stream error: Overloaded");
+
+    let error = stream(&format!(
+      "{}data: {{\"error\":{{\"message\":\"Overloaded\"}}}}\n\n",
+      delta
+    ))
+    .await
+    .unwrap_err();
+    let partial = error.downcast_ref::<PartialAnswer>().unwrap();
+    assert_eq!(partial.answer, "1 2");
+    assert_eq!(partial.error.to_string(), "Stream error: Overloaded");
+
+    // The connection is closed before the announced length.
+    let error = stream(delta).await.unwrap_err();
+    assert_eq!(error.downcast_ref::<PartialAnswer>().unwrap().answer, "1 2");
   }
 }
@@ -8,6 +8,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
   config::{ModelConfig, OpenAI, TemplateConfig},
   infill::Infill,
+  trace,
+  usage::{self, TokenUsage},
 };
 
 #[derive(Clone, PartialEq, Debug, Serialize)]
@@ -34,6 +36,10 @@ struct OpenAICompletionsChoice {
 #[derive(Clone, PartialEq, Debug, Deserialize)]
 struct OpenAICompletionsResponse {
   choices: Vec<OpenAICompletionsChoice>,
+  #[serde(default)]
+  model: Option<String>,
+  #[serde(default)]
+  usage: Option<TokenUsage>,
 }
 
 #[derive(Content)]
@@ -50,8 +56,9 @@ impl Infill for (Arc<TemplateConfig>, Arc<ModelConfig<OpenAI>>) {
     } else {
       request
     };
-    let response = request
-      .json(&OpenAICompletionsRequest {
+    let response: OpenAICompletionsResponse = trace::post(
+      request,
+      &OpenAICompletionsRequest {
         model: &self.1.generation_config.model,
         prompt: self.0 .0.render(&OpenAICompletionsContent { prefix, suffix }),
         temperature: self.1.generation_config.temperature,
@@ -59,11 +66,19 @@ impl Infill for (Arc<TemplateConfig>, Arc<ModelConfig<OpenAI>>) {
         max_tokens: self.1.generation_config.max_tokens,
         stop: &self.1.generation_config.stop,
         seed: self.1.generation_config.seed,
-      })
-      .send()
-      .await?
-      .json::<OpenAICompletionsResponse>()
-      .await?;
+      },
+    )
+    .await?;
+    if let Some(usage) = response.usage {
+      usage::record(
+        usage::model_name(
+          self.1.generation_config.model.as_deref(),
+          response.model.as_deref(),
+          &self.1.url,
+        ),
+        usage.into(),
+      );
+    }
 
     Ok(response.choices.into_iter().map(|choice| choice.text))
   }
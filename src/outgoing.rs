@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+use dashmap::DashMap;
+use lsp_server::{Message, Request as LspRequest, RequestId, Response as LspResponse};
+use lsp_types::request::Request;
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+/// Forgets a request when its response is no longer awaited, e.g. because the awaiting task was cancelled.
+struct Pending<'a> {
+  outgoing: &'a Outgoing,
+  id: RequestId,
+}
+
+impl Drop for Pending<'_> {
+  fn drop(&mut self) {
+    self.outgoing.pending.remove(&self.id);
+  }
+}
+
+#[derive(Debug)]
+pub struct Outgoing {
+  sender: Sender<Message>,
+  next_id: AtomicI32,
+  pending: DashMap<RequestId, oneshot::Sender<LspResponse>>,
+}
+
+impl Outgoing {
+  pub fn new(sender: Sender<Message>) -> Self {
+    Outgoing {
+      sender,
+      next_id: AtomicI32::new(0),
+      pending: Default::default(),
+    }
+  }
+
+  pub async fn request<R: Request>(&self, params: R::Params) -> Result<R::Result> {
+    let id = RequestId::from(self.next_id.fetch_add(1, Ordering::Relaxed));
+    let (response_sender, response_receiver) = oneshot::channel();
+    self.pending.insert(id.clone(), response_sender);
+    let _pending = Pending {
+      outgoing: self,
+      id: id.clone(),
+    };
+    self
+      .sender
+      .send(Message::Request(LspRequest::new(id, R::METHOD.to_string(), params)))?;
+    let response = response_receiver.await?;
+    if let Some(error) = response.error {
+      return Err(anyhow!("{} failed: {}", R::METHOD, error.message));
+    }
+    Ok(serde_json::from_value(response.result.unwrap_or(Value::Null))?)
+  }
+
+  pub fn complete(&self, response: LspResponse) {
+    if let Some((_, response_sender)) = self.pending.remove(&response.id) {
+      let _ = response_sender.send(response);
+    } else {
+      log::warn!("Unexpected response: {:?}", response.id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use lsp_server::{ErrorCode, Message, Response as LspResponse};
+  use lsp_types::{
+    request::{ApplyWorkspaceEdit, Request},
+    ApplyWorkspaceEditParams, ApplyWorkspaceEditResponse, WorkspaceEdit,
+  };
+
+  use super::Outgoing;
+
+  fn params() -> ApplyWorkspaceEditParams {
+    ApplyWorkspaceEditParams {
+      label: None,
+      edit: WorkspaceEdit::default(),
+    }
+  }
+
+  #[tokio::test]
+  async fn request_response() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let outgoing = Outgoing::new(sender);
+    let (first, second, _) = tokio::join!(
+      outgoing.request::<ApplyWorkspaceEdit>(params()),
+      outgoing.request::<ApplyWorkspaceEdit>(params()),
+      async {
+        let mut ids = Vec::new();
+        while ids.len() < 2 {
+          match receiver.try_recv() {
+            Ok(Message::Request(request)) => {
+              assert_eq!(request.method, ApplyWorkspaceEdit::METHOD);
+              ids.push(request.id);
+            }
+            Ok(message) => panic!("Unexpected message: {:?}", message),
+            Err(_) => tokio::task::yield_now().await,
+          }
+        }
+        assert_ne!(ids[0], ids[1]);
+        outgoing.complete(LspResponse::new_err(
+          ids[1].clone(),
+          ErrorCode::RequestFailed as i32,
+          "error".to_string(),
+        ));
+        outgoing.complete(LspResponse::new_ok(
+          ids[0].clone(),
+          ApplyWorkspaceEditResponse {
+            applied: true,
+            failure_reason: None,
+            failed_change: None,
+          },
+        ));
+      }
+    );
+    assert!(first.unwrap().applied);
+    assert!(second.is_err());
+  }
+
+  #[tokio::test]
+  async fn cancelled_request() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let outgoing = Outgoing::new(sender);
+    tokio::select! {
+      biased;
+      _ = outgoing.request::<ApplyWorkspaceEdit>(params()) => panic!("The request was answered"),
+      _ = async {} => {}
+    }
+    assert!(outgoing.pending.is_empty());
+    let Ok(Message::Request(request)) = receiver.try_recv() else {
+      panic!("The request was not sent");
+    };
+    outgoing.complete(LspResponse::new_ok(request.id, ()));
+  }
+}
@@ -0,0 +1,305 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use lsp_server::{Message, Notification as LspNotification};
+use lsp_types::{
+  notification::{Notification, PublishDiagnostics},
+  Diagnostic, DiagnosticSeverity, DidSaveTextDocumentParams, MessageType, Position, PublishDiagnosticsParams, Range,
+  Uri,
+};
+use ramhorns::Content;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use similar::{ChangeTag, TextDiff};
+use tokio::sync::oneshot;
+
+use crate::{chat::Chat, render_messages, trace, State};
+
+pub const SOURCE: &str = "famulus";
+
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Error,
+  #[default]
+  Warning,
+  Information,
+  Hint,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct Comment {
+  pub line: u32,
+  #[serde(default)]
+  pub severity: Severity,
+  pub message: String,
+  #[serde(default)]
+  pub replacement: Option<String>,
+}
+
+/// Formats the changed hunks of `new` compared to `old`, prefixing every line with its 1-based line number in `new`.
+/// Removed lines have no line number.
+pub fn changed_hunks(old: &str, new: &str) -> String {
+  let diff = TextDiff::from_lines(old, new);
+  let mut hunks = String::new();
+  for group in diff.grouped_ops(CONTEXT_LINES) {
+    let (Some(first), Some(last)) = (group.first(), group.last()) else {
+      continue;
+    };
+    let _ = writeln!(
+      hunks,
+      "@@ -{},{} +{},{} @@",
+      first.old_range().start + 1,
+      last.old_range().end - first.old_range().start,
+      first.new_range().start + 1,
+      last.new_range().end - first.new_range().start,
+    );
+    for op in &group {
+      for change in diff.iter_changes(op) {
+        let line = change.value().trim_end_matches(['\r', '\n']);
+        let _ = match (change.tag(), change.new_index()) {
+          (ChangeTag::Delete, _) | (_, None) => writeln!(hunks, "      - {}", line),
+          (ChangeTag::Insert, Some(index)) => writeln!(hunks, "{:>5} + {}", index + 1, line),
+          (ChangeTag::Equal, Some(index)) => writeln!(hunks, "{:>5}   {}", index + 1, line),
+        };
+      }
+    }
+  }
+  hunks
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Comments {
+  List(Vec<Comment>),
+  Object { comments: Vec<Comment> },
+}
+
+/// Parses the comments from either a JSON array or an object with a `comments` array.
+pub fn parse_comments(value: Value) -> Result<Vec<Comment>> {
+  match serde_json::from_value(value)? {
+    Comments::List(comments) | Comments::Object { comments } => Ok(comments),
+  }
+}
+
+/// A suggested replacement of the chars `start..end` of the reviewed version of a document.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Fix {
+  pub version: i32,
+  pub start: usize,
+  pub end: usize,
+  pub replacement: String,
+}
+
+/// Converts a comment on the document at `version` to a diagnostic covering its whole line, or `None` if the line is
+/// not in the document.
+pub fn comment_diagnostic(rope: &Rope, version: i32, comment: Comment) -> Option<Diagnostic> {
+  let line = (comment.line as usize).checked_sub(1)?;
+  if line >= rope.len_lines() {
+    return None;
+  }
+  let text = rope.line(line).to_string();
+  let length = text.trim_end_matches(['\r', '\n']).chars().count();
+  let severity = match comment.severity {
+    Severity::Error => DiagnosticSeverity::ERROR,
+    Severity::Warning => DiagnosticSeverity::WARNING,
+    Severity::Information => DiagnosticSeverity::INFORMATION,
+    Severity::Hint => DiagnosticSeverity::HINT,
+  };
+  Some(Diagnostic {
+    range: Range::new(Position::new(line as u32, 0), Position::new(line as u32, length as u32)),
+    severity: Some(severity),
+    source: Some(SOURCE.to_string()),
+    message: comment.message,
+    data: comment.replacement.map(|replacement| {
+      let start = rope.line_to_char(line);
+      json!(Fix {
+        version,
+        start,
+        end: start + length,
+        replacement,
+      })
+    }),
+    ..Default::default()
+  })
+}
+
+/// Returns the suggested fix attached to a diagnostic published by the reviewer.
+pub fn diagnostic_fix(diagnostic: &Diagnostic) -> Option<Fix> {
+  if diagnostic.source.as_deref() != Some(SOURCE) {
+    return None;
+  }
+  serde_json::from_value(diagnostic.data.clone()?).ok()
+}
+
+#[derive(Content)]
+struct ReviewContent {
+  diff: String,
+  path: String,
+  language_id: String,
+}
+
+impl State {
+  pub fn did_save_text_document(&self, params: DidSaveTextDocumentParams) -> Result<()> {
+    if !self.config.review.enabled {
+      return Ok(());
+    }
+    let uri = params.text_document.uri;
+    let mut document = self
+      .documents
+      .get_mut(&uri)
+      .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+    let diff = changed_hunks(&document.saved.to_string(), &document.rope.to_string());
+    document.saved = document.rope.clone();
+    if diff.is_empty() {
+      return Ok(());
+    }
+    let content = ReviewContent {
+      diff,
+      path: uri.path().to_string(),
+      language_id: document.language_id.clone(),
+    };
+    let messages = render_messages(&self.config.review.messages, &content);
+    let rope = document.rope.clone();
+    let version = document.version;
+    drop(document);
+    let chat = self.config.review.model_config.get_chat(&self.scripts);
+    let state = self.clone();
+    let uri_c = uri.clone();
+    let future = async move {
+      let review_config = &state.config.review;
+      let value = match &review_config.response_format {
+        Some(response_format) => {
+          state
+            .chat_json(chat, messages, response_format, &review_config.extract)
+            .await
+        }
+        None => chat.chat(state.client.clone(), messages).await.and_then(|mut choices| {
+          let choice = review_config.extract.extract(choices.next().unwrap_or_default());
+          Ok(serde_json::from_str(choice.trim())?)
+        }),
+      };
+      let diagnostics = value.and_then(parse_comments).map(|comments| {
+        comments
+          .into_iter()
+          .filter_map(|comment| comment_diagnostic(&rope, version, comment))
+          .collect()
+      });
+      // A newer review of the document may have replaced this one already.
+      state
+        .reviews
+        .remove_if(&uri_c, |_, handle| handle.id() == tokio::task::id());
+      match diagnostics {
+        Ok(diagnostics) => state.publish_diagnostics(uri_c, diagnostics, Some(version)),
+        Err(error) => state.show_message(MessageType::WARNING, format!("Review failed: {}", error)),
+      }
+    };
+    let (start_sender, start_receiver) = oneshot::channel();
+    let handle = tokio::task::spawn(self.scoped(trace::with_document(uri.clone(), async move {
+      // Wait until the handle is registered, so that the review cannot finish before it and leave it behind.
+      let _ = start_receiver.await;
+      future.await
+    })));
+    if let Some(handle) = self.reviews.insert(uri, handle) {
+      handle.abort();
+    }
+    let _ = start_sender.send(());
+    Ok(())
+  }
+
+  pub fn publish_diagnostics(&self, uri: Uri, diagnostics: Vec<Diagnostic>, version: Option<i32>) -> Result<()> {
+    self.sender.send(Message::Notification(LspNotification::new(
+      PublishDiagnostics::METHOD.to_string(),
+      PublishDiagnosticsParams {
+        uri,
+        diagnostics,
+        version,
+      },
+    )))?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use lsp_types::{DiagnosticSeverity, Position, Range};
+  use ropey::Rope;
+  use serde_json::json;
+
+  use super::{changed_hunks, comment_diagnostic, diagnostic_fix, parse_comments, Comment, Fix, Severity};
+
+  #[test]
+  fn hunks() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+    let new = "a\nb\nc\nd\ne\nF\nG\ng\nh\ni\nj\n";
+    assert_eq!(
+      changed_hunks(old, new),
+      "@@ -3,7 +3,8 @@\n    3   c\n    4   d\n    5   e\n      - f\n    6 + F\n    7 + G\n    8   g\n    9   h\n   10   i\n"
+    );
+    assert_eq!(changed_hunks(old, old), "");
+  }
+
+  #[test]
+  fn comments() {
+    let comments = parse_comments(json!([
+      { "line": 2, "severity": "error", "message": "Off by one", "replacement": "  for i in 0..n {" },
+      { "line": 5, "message": "Unused variable" }
+    ]))
+    .unwrap();
+    assert_eq!(
+      comments,
+      vec![
+        Comment {
+          line: 2,
+          severity: Severity::Error,
+          message: "Off by one".to_string(),
+          replacement: Some("  for i in 0..n {".to_string()),
+        },
+        Comment {
+          line: 5,
+          severity: Severity::Warning,
+          message: "Unused variable".to_string(),
+          replacement: None,
+        },
+      ]
+    );
+    assert_eq!(
+      parse_comments(json!({ "comments": [{ "line": 1, "message": "Typo" }] })).unwrap(),
+      vec![Comment {
+        line: 1,
+        severity: Severity::Warning,
+        message: "Typo".to_string(),
+        replacement: None,
+      }]
+    );
+    assert!(parse_comments(json!({ "review": "Looks good to me!" })).is_err());
+  }
+
+  #[test]
+  fn diagnostics() {
+    let rope = Rope::from_str("fn main() {\n  for i in 0..=n {\n  }\n}\n");
+    let comment = |line| Comment {
+      line,
+      severity: Severity::Error,
+      message: "Off by one".to_string(),
+      replacement: Some("  for i in 0..n {".to_string()),
+    };
+    let diagnostic = comment_diagnostic(&rope, 3, comment(2)).unwrap();
+    assert_eq!(diagnostic.range, Range::new(Position::new(1, 0), Position::new(1, 18)));
+    assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+    assert_eq!(
+      diagnostic_fix(&diagnostic),
+      Some(Fix {
+        version: 3,
+        start: 12,
+        end: 30,
+        replacement: "  for i in 0..n {".to_string(),
+      })
+    );
+    assert_eq!(comment_diagnostic(&rope, 3, comment(0)), None);
+    assert_eq!(comment_diagnostic(&rope, 3, comment(6)), None);
+  }
+}
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use lsp_server::{RequestId, Response as LspResponse};
+use lsp_types::{Location, MessageType, ProgressToken, Uri};
+use ramhorns::Content;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+  config::ExtractConfig,
+  diff,
+  document::{char_to_position, position_to_char},
+  extract, render_messages, uri_to_path, State, REWRITE_CONFLICT_MESSAGE,
+};
+
+const REWRITE_FILES_EMPTY_MESSAGE: &str = "Rewrite discarded: the answer contains none of the files";
+const REWRITE_FILES_MISSING_MESSAGE: &str = "The answer contains no code block for ";
+
+#[derive(Content)]
+struct FileContent {
+  tag: String,
+  path: String,
+  language_id: String,
+  selection: String,
+}
+
+#[derive(Content)]
+struct FilesContent {
+  prompt: String,
+  files: Vec<FileContent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RewriteTarget {
+  Location(Location),
+  Document(Uri),
+}
+
+struct RewriteFile {
+  tag: String,
+  uri: Uri,
+  version: i32,
+  start_index: usize,
+  end_index: usize,
+  original: String,
+}
+
+impl State {
+  pub fn rewrite_files(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let [targets, prompt] = TryInto::<[_; 2]>::try_into(arguments)
+      .map_err(|arguments| anyhow!("Wrong number of arguments: {}", arguments.len()))?;
+    let targets: Vec<RewriteTarget> = serde_json::from_value(targets)?;
+    let prompt: String = serde_json::from_value(prompt)?;
+    let mut files = Vec::new();
+    let mut contents = Vec::new();
+    for target in targets {
+      let (uri, range) = match target {
+        RewriteTarget::Location(location) => (location.uri, Some(location.range)),
+        RewriteTarget::Document(uri) => (uri, None),
+      };
+      let document = self
+        .documents
+        .get(&uri)
+        .ok_or_else(|| anyhow!("Missing document: {}", uri.as_str()))?;
+      let path = self.relative_path(&uri);
+      let (start_index, end_index, tag) = match range {
+        Some(range) => (
+          position_to_char(&document.rope, range.start),
+          position_to_char(&document.rope, range.end),
+          format!("{}:{}-{}", path, range.start.line + 1, range.end.line + 1),
+        ),
+        None => (0, document.rope.len_chars(), path.clone()),
+      };
+      let original = document.rope.slice(start_index..end_index).to_string();
+      contents.push(FileContent {
+        tag: tag.clone(),
+        path,
+        language_id: document.language_id.clone(),
+        selection: original.clone(),
+      });
+      files.push(RewriteFile {
+        tag,
+        uri: uri.clone(),
+        version: document.version,
+        start_index,
+        end_index,
+        original,
+      });
+    }
+    let content = FilesContent {
+      prompt,
+      files: contents,
+    };
+    let messages = render_messages(&self.config.rewrite_files.messages, &content);
+    let chat = self.config.rewrite_files.model_config.get_chat(&self.scripts);
+    let tools = self.config.rewrite_files.tools;
+    if tools && self.config.rewrite_files.response_format.is_some() {
+      return Err(anyhow!("Tools can't be used with a response format"));
+    }
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let future = async move {
+      let answers = match &state.config.rewrite_files.response_format {
+        Some(response_format) => {
+          state.begin_progress(&request_id_c, work_done_token, "Rewrite").await;
+          let answers = state
+            .chat_json(chat, messages, response_format, &ExtractConfig::None)
+            .await
+            .and_then(extract::parse_files);
+          state.end_progress(&request_id_c)?;
+          answers
+        }
+        None => state
+          .run_chat(&request_id_c, work_done_token, "Rewrite", chat, messages, tools)
+          .await
+          .map(|choice| {
+            extract::code_blocks(&choice.unwrap_or_default())
+              .into_iter()
+              .map(|(info, content)| (info.to_string(), content.to_string()))
+              .collect::<Vec<_>>()
+          }),
+      };
+      let answers = match answers {
+        Ok(answers) => {
+          state.respond(LspResponse::new_ok(request_id_c, ()))?;
+          answers
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+          return Ok(());
+        }
+      };
+      let mut edits = Vec::new();
+      let mut missing = Vec::new();
+      for file in files {
+        let Some((_, new)) = answers.iter().find(|(info, _)| extract::has_tag(info, &file.tag)) else {
+          missing.push(file.tag);
+          continue;
+        };
+        let Some(document) = state.documents.get(&file.uri) else {
+          continue;
+        };
+        let Some((start_index, _)) = document.rebase(file.version, file.start_index, file.end_index) else {
+          drop(document);
+          return state.show_message(MessageType::WARNING, REWRITE_CONFLICT_MESSAGE);
+        };
+        let start = char_to_position(&document.rope, start_index);
+        edits.push((
+          file.uri,
+          Some(document.version),
+          diff::diff_edits(&file.original, new, start),
+        ));
+      }
+      if edits.is_empty() {
+        return state.show_message(MessageType::WARNING, REWRITE_FILES_EMPTY_MESSAGE);
+      }
+      if !missing.is_empty() {
+        state.show_message(
+          MessageType::WARNING,
+          format!("{}{}", REWRITE_FILES_MISSING_MESSAGE, missing.join(", ")),
+        )?;
+      }
+      state.apply_workspace_edit(state.documents_edit(edits, None)).await
+    };
+    self.spawn_task(request_id, future);
+    Ok(())
+  }
+
+  fn relative_path(&self, uri: &Uri) -> String {
+    let Some(path) = uri_to_path(uri) else {
+      return uri.as_str().to_string();
+    };
+    self
+      .workspace_folders
+      .iter()
+      .find_map(|folder| path.strip_prefix(folder).ok())
+      .unwrap_or(&path)
+      .display()
+      .to_string()
+  }
+}
@@ -0,0 +1,140 @@
+use serde_json::Value;
+
+use crate::config::ResponseFormatConfig;
+
+fn type_matches(typ: &str, value: &Value) -> bool {
+  match typ {
+    "object" => value.is_object(),
+    "array" => value.is_array(),
+    "string" => value.is_string(),
+    "number" => value.is_number(),
+    "integer" => value.is_i64() || value.is_u64(),
+    "boolean" => value.is_boolean(),
+    "null" => value.is_null(),
+    _ => true,
+  }
+}
+
+/// Validates `value` against the `type`, `enum`, `properties`, `required`, `additionalProperties` and `items` keywords
+/// of `schema`. Other keywords are ignored.
+pub fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+  let types = match schema.get("type") {
+    Some(Value::String(typ)) => vec![typ.as_str()],
+    Some(Value::Array(types)) => types.iter().filter_map(|typ| typ.as_str()).collect(),
+    _ => Vec::new(),
+  };
+  if !types.is_empty() && !types.iter().any(|typ| type_matches(typ, value)) {
+    return Err(format!("{}: expected {}", path, types.join(" or ")));
+  }
+  if let Some(Value::Array(values)) = schema.get("enum") {
+    if !values.contains(value) {
+      return Err(format!("{}: unexpected value {}", path, value));
+    }
+  }
+  if let Value::Object(object) = value {
+    let properties = schema.get("properties").and_then(|properties| properties.as_object());
+    if let Some(Value::Array(required)) = schema.get("required") {
+      for name in required.iter().filter_map(|name| name.as_str()) {
+        if !object.contains_key(name) {
+          return Err(format!("{}: missing property {}", path, name));
+        }
+      }
+    }
+    for (name, property) in object {
+      match properties.and_then(|properties| properties.get(name)) {
+        Some(property_schema) => validate(property_schema, property, &format!("{}.{}", path, name))?,
+        None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+          return Err(format!("{}: unexpected property {}", path, name));
+        }
+        None => {}
+      }
+    }
+  }
+  if let (Value::Array(items), Some(items_schema)) = (value, schema.get("items")) {
+    for (index, item) in items.iter().enumerate() {
+      validate(items_schema, item, &format!("{}[{}]", path, index))?;
+    }
+  }
+  Ok(())
+}
+
+impl ResponseFormatConfig {
+  pub fn validate(&self, value: &Value) -> Result<(), String> {
+    match self {
+      ResponseFormatConfig::JsonObject if value.is_object() => Ok(()),
+      ResponseFormatConfig::JsonObject => Err("$: expected object".to_string()),
+      ResponseFormatConfig::JsonSchema { schema, .. } => validate(schema, value, "$"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::config::ResponseFormatConfig;
+
+  fn format() -> ResponseFormatConfig {
+    ResponseFormatConfig::JsonSchema {
+      name: "comments".to_string(),
+      schema: json!({
+        "type": "object",
+        "properties": {
+          "comments": {
+            "type": "array",
+            "items": {
+              "type": "object",
+              "properties": {
+                "line": { "type": "integer" },
+                "severity": { "enum": ["error", "warning"] },
+                "message": { "type": "string" },
+                "replacement": { "type": ["string", "null"] }
+              },
+              "required": ["line", "message"],
+              "additionalProperties": false
+            }
+          }
+        },
+        "required": ["comments"]
+      }),
+      strict: false,
+    }
+  }
+
+  #[test]
+  fn valid() {
+    let value = json!({
+      "comments": [
+        { "line": 1, "severity": "error", "message": "a", "replacement": null },
+        { "line": 2, "message": "b", "replacement": "c" }
+      ]
+    });
+    assert_eq!(format().validate(&value), Ok(()));
+    assert_eq!(ResponseFormatConfig::JsonObject.validate(&value), Ok(()));
+  }
+
+  #[test]
+  fn invalid() {
+    assert_eq!(format().validate(&json!([])), Err("$: expected object".to_string()));
+    assert_eq!(
+      format().validate(&json!({})),
+      Err("$: missing property comments".to_string())
+    );
+    assert_eq!(
+      format().validate(&json!({ "comments": [{ "line": 1.5, "message": "a" }] })),
+      Err("$.comments[0].line: expected integer".to_string())
+    );
+    assert_eq!(
+      format().validate(&json!({ "comments": [{ "line": 1, "message": "a", "severity": "info" }] })),
+      Err("$.comments[0].severity: unexpected value \"info\"".to_string())
+    );
+    assert_eq!(
+      format().validate(&json!({ "comments": [{ "line": 1, "message": "a", "fix": "b" }] })),
+      Err("$.comments[0]: unexpected property fix".to_string())
+    );
+    assert_eq!(
+      ResponseFormatConfig::JsonObject.validate(&json!([])),
+      Err("$: expected object".to_string())
+    );
+  }
+}
@@ -0,0 +1,231 @@
+use std::{collections::VecDeque, sync::atomic::Ordering, time::Instant};
+
+use anyhow::{anyhow, Result};
+use lsp_server::{ErrorCode, Message, RequestId, Response as LspResponse};
+use lsp_types::{Location, ProgressToken};
+use ropey::Rope;
+use serde_json::Value;
+
+use crate::{document::position_to_char, render_messages, SelectionContent, State, NO_ANSWER_MESSAGE};
+
+const SYSTEM_ROLE: &str = "system";
+const ASSISTANT_ROLE: &str = "assistant";
+
+#[derive(Debug)]
+pub struct Session {
+  turns: VecDeque<Vec<(String, String)>>,
+  last_used: Instant,
+}
+
+impl Default for Session {
+  fn default() -> Self {
+    Session {
+      turns: VecDeque::new(),
+      last_used: Instant::now(),
+    }
+  }
+}
+
+impl Session {
+  /// When the session was started, answered or reset last.
+  pub fn last_used(&self) -> Instant {
+    self.last_used
+  }
+
+  /// Returns the messages of a new turn with the history inserted after its system messages.
+  pub fn messages(&self, turn: &[(String, String)]) -> Vec<(String, String)> {
+    let (system, rest): (Vec<_>, Vec<_>) = turn.iter().cloned().partition(|(role, _)| role == SYSTEM_ROLE);
+    system
+      .into_iter()
+      .chain(self.turns.iter().flatten().cloned())
+      .chain(rest)
+      .collect()
+  }
+
+  /// Records a turn and its answer, keeping at most `limit` turns. System messages are not recorded.
+  pub fn push(&mut self, turn: Vec<(String, String)>, answer: String, limit: usize) {
+    let mut messages = turn
+      .into_iter()
+      .filter(|(role, _)| role != SYSTEM_ROLE)
+      .collect::<Vec<_>>();
+    messages.push((ASSISTANT_ROLE.to_string(), answer));
+    self.turns.push_back(messages);
+    while self.turns.len() > limit {
+      self.turns.pop_front();
+    }
+    self.last_used = Instant::now();
+  }
+
+  pub fn reset(&mut self) {
+    self.turns.clear();
+    self.last_used = Instant::now();
+  }
+}
+
+impl State {
+  pub fn session_start(&self, request_id: RequestId) -> Result<()> {
+    let id = format!("famulus-session-{}", self.next_session.fetch_add(1, Ordering::Relaxed));
+    // Forget the least recently used sessions beyond the limit.
+    while self.sessions.len() >= self.config.session.session_limit.max(1) {
+      let oldest = self
+        .sessions
+        .iter()
+        .min_by_key(|session| session.last_used())
+        .map(|session| session.key().clone());
+      match oldest {
+        Some(oldest) => self.sessions.remove(&oldest),
+        None => break,
+      };
+    }
+    self.sessions.insert(id.clone(), Session::default());
+    self
+      .sender
+      .send(Message::Response(LspResponse::new_ok(request_id, id)))?;
+    Ok(())
+  }
+
+  pub fn session_reset(&self, request_id: RequestId, arguments: Vec<Value>) -> Result<()> {
+    let [id] = TryInto::<[_; 1]>::try_into(arguments)
+      .map_err(|arguments| anyhow!("Wrong number of arguments: {}", arguments.len()))?;
+    let id: String = serde_json::from_value(id)?;
+    let response = match self.sessions.get_mut(&id) {
+      Some(mut session) => {
+        session.reset();
+        LspResponse::new_ok(request_id, ())
+      }
+      None => LspResponse::new_err(
+        request_id,
+        ErrorCode::InvalidParams as i32,
+        format!("Unknown session: {}", id),
+      ),
+    };
+    self.sender.send(Message::Response(response))?;
+    Ok(())
+  }
+
+  pub fn session_send(
+    &self,
+    request_id: RequestId,
+    arguments: Vec<Value>,
+    work_done_token: Option<ProgressToken>,
+  ) -> Result<()> {
+    let (id, prompt, location) = match TryInto::<[_; 3]>::try_into(arguments) {
+      Ok([id, prompt, location]) => (id, prompt, Some(serde_json::from_value::<Location>(location)?)),
+      Err(arguments) => match TryInto::<[_; 2]>::try_into(arguments) {
+        Ok([id, prompt]) => (id, prompt, None),
+        Err(arguments) => return Err(anyhow!("Wrong number of arguments: {}", arguments.len())),
+      },
+    };
+    let id: String = serde_json::from_value(id)?;
+    let prompt: String = serde_json::from_value(prompt)?;
+    let turn: Vec<(String, String)> = match location {
+      Some(location) => {
+        let document = self
+          .documents
+          .get(&location.uri)
+          .ok_or_else(|| anyhow!("Missing document: {}", location.uri.as_str()))?;
+        let start_index = position_to_char(&document.rope, location.range.start);
+        let end_index = position_to_char(&document.rope, location.range.end);
+        let content = SelectionContent {
+          prompt,
+          selection: document.rope.slice(start_index..end_index).into(),
+          prefix: document.rope.slice(..start_index).into(),
+          suffix: document.rope.slice(end_index..).into(),
+        };
+        render_messages(&self.config.session.messages, &content)
+      }
+      None => {
+        let rope = Rope::new();
+        let content = SelectionContent {
+          prompt,
+          selection: rope.slice(..).into(),
+          prefix: rope.slice(..).into(),
+          suffix: rope.slice(..).into(),
+        };
+        render_messages(&self.config.session.messages, &content)
+      }
+    };
+    let Some(messages) = self.sessions.get(&id).map(|session| session.messages(&turn)) else {
+      self.sender.send(Message::Response(LspResponse::new_err(
+        request_id,
+        ErrorCode::InvalidParams as i32,
+        format!("Unknown session: {}", id),
+      )))?;
+      return Ok(());
+    };
+    let chat = self.config.session.model_config.get_chat(&self.scripts);
+    let state = self.clone();
+    let request_id_c = request_id.clone();
+    let future = async move {
+      let choice = state
+        .run_chat(&request_id_c, work_done_token, "Chat", chat, messages, false)
+        .await;
+      match choice.and_then(|choice| choice.ok_or_else(|| anyhow!(NO_ANSWER_MESSAGE))) {
+        Ok(answer) => {
+          if let Some(mut session) = state.sessions.get_mut(&id) {
+            session.push(turn, answer.clone(), state.config.session.history_limit);
+          }
+          state.respond(LspResponse::new_ok(request_id_c.clone(), &answer))?;
+          state
+            .show_answer(state.config.session.output, &format!("{}.md", id), answer)
+            .await?;
+        }
+        Err(error) => {
+          state.respond_failed(request_id_c, error)?;
+        }
+      }
+      Ok(())
+    };
+    self.spawn_task(request_id, future);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Session;
+
+  fn turn(prompt: &str) -> Vec<(String, String)> {
+    vec![
+      ("system".to_string(), "Be brief.".to_string()),
+      ("user".to_string(), prompt.to_string()),
+    ]
+  }
+
+  fn message(role: &str, content: &str) -> (String, String) {
+    (role.to_string(), content.to_string())
+  }
+
+  #[test]
+  fn history() {
+    let mut session = Session::default();
+    assert_eq!(session.messages(&turn("a")), turn("a"));
+    session.push(turn("a"), "A".to_string(), 2);
+    session.push(turn("b"), "B".to_string(), 2);
+    assert_eq!(
+      session.messages(&turn("c")),
+      vec![
+        message("system", "Be brief."),
+        message("user", "a"),
+        message("assistant", "A"),
+        message("user", "b"),
+        message("assistant", "B"),
+        message("user", "c"),
+      ]
+    );
+    session.push(turn("c"), "C".to_string(), 2);
+    assert_eq!(
+      session.messages(&turn("d")),
+      vec![
+        message("system", "Be brief."),
+        message("user", "b"),
+        message("assistant", "B"),
+        message("user", "c"),
+        message("assistant", "C"),
+        message("user", "d"),
+      ]
+    );
+    session.reset();
+    assert_eq!(session.messages(&turn("e")), turn("e"));
+  }
+}
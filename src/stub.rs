@@ -0,0 +1,72 @@
+use std::{
+  io::{BufRead, BufReader, Read, Write},
+  net::{TcpListener, TcpStream},
+  sync::mpsc,
+  thread,
+  time::Duration,
+};
+
+use serde_json::Value;
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read_body(reader: &mut BufReader<TcpStream>) -> Value {
+  let mut content_length = 0;
+  loop {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("content-length") {
+        content_length = value.trim().parse().unwrap();
+      }
+    }
+  }
+  let mut body = vec![0; content_length];
+  reader.read_exact(&mut body).unwrap();
+  serde_json::from_slice(&body).unwrap()
+}
+
+/// Answers every HTTP request with `response`, sending the request bodies to the returned receiver.
+pub fn stub_server(response: Value) -> (String, mpsc::Receiver<Value>) {
+  let response = response.to_string();
+  raw_server(format!(
+    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    response.len(),
+    response
+  ))
+}
+
+/// Answers every HTTP request with the raw `response` and closes the connection, sending the request bodies to the
+/// returned receiver.
+pub fn raw_server(response: String) -> (String, mpsc::Receiver<Value>) {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut reader = BufReader::new(stream.unwrap());
+      if sender.send(read_body(&mut reader)).is_err() {
+        return;
+      }
+      reader.get_mut().write_all(response.as_bytes()).unwrap();
+    }
+  });
+  (url, receiver)
+}
+
+/// Accepts HTTP requests and never answers them.
+pub fn silent_server() -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  thread::spawn(move || {
+    let mut streams = Vec::new();
+    for stream in listener.incoming() {
+      streams.push(stream);
+    }
+  });
+  url
+}
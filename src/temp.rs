@@ -0,0 +1,104 @@
+use std::{
+  collections::hash_map::RandomState,
+  env,
+  fs::{self, DirBuilder, OpenOptions},
+  hash::{BuildHasher, Hasher},
+  io::{ErrorKind, Write},
+  path::PathBuf,
+  process,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+  },
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+use anyhow::{anyhow, Result};
+use lsp_types::Uri;
+
+use crate::path_to_uri;
+
+const CREATE_ATTEMPTS: usize = 16;
+
+/// A private directory for the documents shown to the user, created on first use and removed with the server state.
+#[derive(Debug, Default)]
+pub struct TempDirectory {
+  path: Mutex<Option<PathBuf>>,
+  next: AtomicUsize,
+}
+
+/// Creates a new directory with an unpredictable name that only the current user can access.
+fn create_directory() -> Result<PathBuf> {
+  for _ in 0..CREATE_ATTEMPTS {
+    let suffix = RandomState::new().build_hasher().finish();
+    let path = env::temp_dir().join(format!("famulus-{}-{:016x}", process::id(), suffix));
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    match builder.create(&path) {
+      Ok(()) => return Ok(path),
+      Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+      Err(error) => return Err(error.into()),
+    }
+  }
+  Err(anyhow!("Failed to create a temporary directory"))
+}
+
+impl TempDirectory {
+  /// Writes `text` to a new file ending with `name`, returning its path and URI.
+  pub fn write(&self, name: &str, text: &str) -> Result<(PathBuf, Uri)> {
+    let name = name.replace(
+      |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-' && c != '_',
+      "_",
+    );
+    let mut directory = self.path.lock().unwrap_or_else(|error| error.into_inner());
+    let directory = match &*directory {
+      Some(directory) => directory.clone(),
+      None => directory.insert(create_directory()?).clone(),
+    };
+    let path = directory.join(format!("{}-{}", self.next.fetch_add(1, Ordering::Relaxed), name));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&path)?.write_all(text.as_bytes())?;
+    Ok((path.clone(), path_to_uri(&path)?))
+  }
+}
+
+impl Drop for TempDirectory {
+  fn drop(&mut self) {
+    if let Some(path) = self.path.get_mut().unwrap_or_else(|error| error.into_inner()) {
+      let _ = fs::remove_dir_all(path);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  #[cfg(unix)]
+  use std::os::unix::fs::PermissionsExt;
+
+  use super::TempDirectory;
+
+  #[test]
+  fn temp_documents() {
+    let temp = TempDirectory::default();
+    let (first, uri) = temp.write("a/b.rs", "fn a() {}\n").unwrap();
+    let (second, _) = temp.write("a/b.rs", "fn b() {}\n").unwrap();
+    assert_ne!(first, second);
+    assert!(uri.as_str().ends_with("-a_b.rs"));
+    assert_eq!(fs::read_to_string(&first).unwrap(), "fn a() {}\n");
+    let directory = first.parent().unwrap().to_path_buf();
+    #[cfg(unix)]
+    {
+      assert_eq!(fs::metadata(&directory).unwrap().permissions().mode() & 0o777, 0o700);
+      assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    drop(temp);
+    assert!(!directory.exists());
+  }
+}
@@ -0,0 +1,295 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use lsp_types::Uri;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+  chat::{ToolCall, ToolDefinition},
+  document::Document,
+};
+
+pub const MAX_TOOL_ROUNDS: usize = 10;
+const MAX_FILE_SIZE: u64 = 1 << 20;
+const MAX_GREP_MATCHES: usize = 100;
+
+#[derive(Deserialize)]
+struct ReadDocumentArguments {
+  uri: String,
+}
+
+#[derive(Deserialize)]
+struct ReadFileArguments {
+  path: String,
+}
+
+#[derive(Deserialize)]
+struct GrepArguments {
+  pattern: String,
+  #[serde(default)]
+  path: Option<String>,
+}
+
+/// Read-only tools the model may call while answering.
+#[derive(Clone)]
+pub struct Tools {
+  documents: Arc<DashMap<Uri, Document>>,
+  workspace_folders: Arc<Vec<PathBuf>>,
+}
+
+impl Tools {
+  pub fn new(documents: Arc<DashMap<Uri, Document>>, workspace_folders: Arc<Vec<PathBuf>>) -> Self {
+    Tools {
+      documents,
+      workspace_folders,
+    }
+  }
+
+  pub fn definitions(&self) -> Vec<ToolDefinition> {
+    let uris = self
+      .documents
+      .iter()
+      .map(|entry| entry.key().as_str().to_string())
+      .collect::<Vec<_>>();
+    vec![
+      ToolDefinition {
+        name: "read_document".to_string(),
+        description: format!(
+          "Returns the current text of a document open in the editor. Open documents: {}",
+          uris.join(", ")
+        ),
+        parameters: json!({
+          "type": "object",
+          "properties": {
+            "uri": { "type": "string", "description": "URI of the document" }
+          },
+          "required": ["uri"]
+        }),
+      },
+      ToolDefinition {
+        name: "read_file".to_string(),
+        description: "Returns the content of a file in the workspace.".to_string(),
+        parameters: json!({
+          "type": "object",
+          "properties": {
+            "path": { "type": "string", "description": "Path relative to the workspace folder" }
+          },
+          "required": ["path"]
+        }),
+      },
+      ToolDefinition {
+        name: "grep".to_string(),
+        description: format!(
+          "Searches the files in the workspace for a regular expression and returns up to {} matching lines as \
+           path:line:text.",
+          MAX_GREP_MATCHES
+        ),
+        parameters: json!({
+          "type": "object",
+          "properties": {
+            "pattern": { "type": "string", "description": "Regular expression" },
+            "path": { "type": "string", "description": "Directory relative to the workspace folder to search in" }
+          },
+          "required": ["pattern"]
+        }),
+      },
+    ]
+  }
+
+  /// Runs a tool call, returning its result or error as text for the model.
+  pub fn call(&self, tool_call: &ToolCall) -> String {
+    let result = match tool_call.name.as_str() {
+      "read_document" => serde_json::from_str(&tool_call.arguments)
+        .map_err(|error| error.into())
+        .and_then(|arguments| self.read_document(arguments)),
+      "read_file" => serde_json::from_str(&tool_call.arguments)
+        .map_err(|error| error.into())
+        .and_then(|arguments| self.read_file(arguments)),
+      "grep" => serde_json::from_str(&tool_call.arguments)
+        .map_err(|error| error.into())
+        .and_then(|arguments| self.grep(arguments)),
+      name => Err(anyhow!("Unknown tool: {}", name)),
+    };
+    result.unwrap_or_else(|error| format!("Error: {}", error))
+  }
+
+  fn read_document(&self, arguments: ReadDocumentArguments) -> Result<String> {
+    let uri = Uri::from_str(&arguments.uri)?;
+    let document = self
+      .documents
+      .get(&uri)
+      .ok_or_else(|| anyhow!("Document is not open: {}", arguments.uri))?;
+    Ok(document.rope.to_string())
+  }
+
+  fn read_file(&self, arguments: ReadFileArguments) -> Result<String> {
+    let path = self.resolve(&arguments.path)?;
+    if fs::metadata(&path)?.len() > MAX_FILE_SIZE {
+      return Err(anyhow!("File is too large: {}", arguments.path));
+    }
+    Ok(fs::read_to_string(path)?)
+  }
+
+  fn grep(&self, arguments: GrepArguments) -> Result<String> {
+    let regex = Regex::new(&arguments.pattern)?;
+    let root = self.resolve(arguments.path.as_deref().unwrap_or("."))?;
+    let base = self
+      .canonical_folders()
+      .find(|folder| root.starts_with(folder))
+      .unwrap_or_else(|| root.clone());
+    let mut matches = Vec::new();
+    grep_path(&regex, &root, &base, &mut matches);
+    Ok(matches.join("\n"))
+  }
+
+  fn canonical_folders(&self) -> impl Iterator<Item = PathBuf> + '_ {
+    self
+      .workspace_folders
+      .iter()
+      .filter_map(|folder| folder.canonicalize().ok())
+  }
+
+  /// Resolves `path` relative to the first workspace folder, rejecting paths outside of all workspace folders.
+  fn resolve(&self, path: &str) -> Result<PathBuf> {
+    let folder = self
+      .workspace_folders
+      .first()
+      .ok_or_else(|| anyhow!("No workspace folder"))?;
+    let path = folder.join(path).canonicalize()?;
+    let inside = self.canonical_folders().any(|folder| path.starts_with(folder));
+    if !inside {
+      return Err(anyhow!("Path is outside of the workspace: {}", path.display()));
+    }
+    Ok(path)
+  }
+}
+
+fn is_hidden(path: &Path) -> bool {
+  path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .is_some_and(|name| name.starts_with('.'))
+}
+
+fn grep_path(regex: &Regex, path: &Path, base: &Path, matches: &mut Vec<String>) {
+  if matches.len() >= MAX_GREP_MATCHES {
+    return;
+  }
+  // Symlinks are skipped as they may point outside of the workspace.
+  let Ok(metadata) = fs::symlink_metadata(path) else {
+    return;
+  };
+  if metadata.is_dir() {
+    let Ok(entries) = fs::read_dir(path) else {
+      return;
+    };
+    let mut paths = entries
+      .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+      .filter(|path| !is_hidden(path))
+      .collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+      grep_path(regex, &path, base, matches);
+    }
+    return;
+  }
+  if !metadata.is_file() || metadata.len() > MAX_FILE_SIZE {
+    return;
+  }
+  let Ok(text) = fs::read_to_string(path) else {
+    return;
+  };
+  let relative = path.strip_prefix(base).unwrap_or(path).display().to_string();
+  for (index, line) in text.lines().enumerate() {
+    if matches.len() >= MAX_GREP_MATCHES {
+      return;
+    }
+    if regex.is_match(line) {
+      matches.push(format!("{}:{}:{}", relative, index + 1, line));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, sync::Arc};
+
+  use dashmap::DashMap;
+  use ropey::Rope;
+
+  use crate::{chat::ToolCall, document::Document};
+
+  use super::Tools;
+
+  fn call(tools: &Tools, name: &str, arguments: &str) -> String {
+    tools.call(&ToolCall {
+      id: "call".to_string(),
+      name: name.to_string(),
+      arguments: arguments.to_string(),
+    })
+  }
+
+  #[test]
+  fn tools() {
+    let folder = env::temp_dir().join(format!("famulus-tools-{}", std::process::id()));
+    fs::create_dir_all(folder.join("src")).unwrap();
+    fs::create_dir_all(folder.join(".git")).unwrap();
+    fs::write(folder.join("src/a.rs"), "fn a() {}\nfn b() {}\n").unwrap();
+    fs::write(folder.join(".git/config"), "fn hidden() {}\n").unwrap();
+    let folder = folder.canonicalize().unwrap();
+
+    let documents = DashMap::new();
+    documents.insert(
+      "file:///open.rs".parse().unwrap(),
+      Document::new(Rope::from_str("open"), 1, "rust".to_string()),
+    );
+    let tools = Tools::new(Arc::new(documents), Arc::new(vec![folder.clone()]));
+    assert_eq!(tools.definitions().len(), 3);
+
+    assert_eq!(call(&tools, "read_document", r#"{ "uri": "file:///open.rs" }"#), "open");
+    assert!(call(&tools, "read_document", r#"{ "uri": "file:///closed.rs" }"#).starts_with("Error: "));
+    assert_eq!(
+      call(&tools, "read_file", r#"{ "path": "src/a.rs" }"#),
+      "fn a() {}\nfn b() {}\n"
+    );
+    assert!(call(&tools, "read_file", r#"{ "path": "../outside" }"#).starts_with("Error: "));
+    assert_eq!(
+      call(&tools, "grep", r#"{ "pattern": "fn \\w+\\(" }"#),
+      "src/a.rs:1:fn a() {}\nsrc/a.rs:2:fn b() {}"
+    );
+    assert!(call(&tools, "write_file", "{}").starts_with("Error: "));
+
+    fs::remove_dir_all(folder).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks() {
+    let folder = env::temp_dir().join(format!("famulus-tools-symlinks-{}", std::process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    fs::write(folder.join("a.rs"), "fn a() {}\n").unwrap();
+    let outside = env::temp_dir().join(format!("famulus-tools-outside-{}", std::process::id()));
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("secret.rs"), "fn secret() {}\n").unwrap();
+    std::os::unix::fs::symlink(&outside, folder.join("link")).unwrap();
+    let folder = folder.canonicalize().unwrap();
+
+    let tools = Tools::new(Default::default(), Arc::new(vec![folder.clone()]));
+    assert_eq!(
+      call(&tools, "grep", r#"{ "pattern": "fn \\w+\\(" }"#),
+      "a.rs:1:fn a() {}"
+    );
+    assert!(call(&tools, "read_file", r#"{ "path": "link/secret.rs" }"#).starts_with("Error: "));
+
+    fs::remove_dir_all(folder).unwrap();
+    fs::remove_dir_all(outside).unwrap();
+  }
+}
@@ -0,0 +1,261 @@
+use std::{
+  fs::OpenOptions,
+  future::Future,
+  io::Write,
+  sync::{mpsc, Arc},
+  thread,
+  time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use lsp_types::Uri;
+use reqwest::{header::AUTHORIZATION, RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::TraceConfig;
+
+/// Fields of the provider wire formats that hold prompts, code or answers.
+const CONTENT_FIELDS: &[&str] = &[
+  "prompt",
+  "suffix",
+  "input_prefix",
+  "input_suffix",
+  "content",
+  "text",
+  "response",
+  "arguments",
+];
+const REDACTED: &str = "[REDACTED]";
+
+/// Where the traces go. The lines are written by a separate thread, so tracing never blocks the async workers.
+#[derive(Debug)]
+pub struct Sink {
+  lines: mpsc::Sender<String>,
+  redact_content: bool,
+}
+
+impl Sink {
+  /// Opens the trace file of `config`, or returns `None` if tracing is disabled.
+  pub fn open(config: &TraceConfig) -> Result<Option<Sink>> {
+    let Some(path) = &config.path else {
+      return Ok(None);
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let (lines, receiver) = mpsc::channel::<String>();
+    thread::spawn(move || {
+      for line in receiver {
+        if let Err(error) = writeln!(file, "{}", line) {
+          log::warn!("Failed to write trace: {}", error);
+        }
+      }
+    });
+    Ok(Some(Sink {
+      lines,
+      redact_content: config.redact_content,
+    }))
+  }
+}
+
+tokio::task_local! {
+  static SINK: Option<Arc<Sink>>;
+  static DOCUMENT: Uri;
+}
+
+fn sink() -> Option<Arc<Sink>> {
+  SINK.try_with(Clone::clone).ok().flatten()
+}
+
+/// Runs `future` with its traces going to `sink`.
+pub fn with_sink<F: Future>(sink: Option<Arc<Sink>>, future: F) -> impl Future<Output = F::Output> {
+  SINK.scope(sink, future)
+}
+
+/// Runs `future` with `uri` recorded as the document of its traces.
+pub fn with_document<F: Future>(uri: Uri, future: F) -> impl Future<Output = F::Output> {
+  DOCUMENT.scope(uri, future)
+}
+
+fn redact_content(value: &mut Value) {
+  match value {
+    Value::Object(object) => {
+      for (key, value) in object {
+        match value {
+          Value::String(text) if CONTENT_FIELDS.contains(&key.as_str()) => {
+            *text = format!("[{} chars]", text.chars().count());
+          }
+          value => redact_content(value),
+        }
+      }
+    }
+    Value::Array(values) => values.iter_mut().for_each(redact_content),
+    _ => {}
+  }
+}
+
+#[derive(Serialize)]
+struct Entry {
+  time: f64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  uri: Option<String>,
+  url: String,
+  request: Value,
+  response: Value,
+  latency_ms: u128,
+}
+
+/// A request being traced. Does nothing if tracing is disabled.
+pub struct Trace {
+  url: String,
+  api_key: Option<String>,
+  request: Value,
+  start: Instant,
+}
+
+impl Trace {
+  pub fn finish(self, mut response: Value) {
+    let Some(sink) = sink() else {
+      return;
+    };
+    let mut request = self.request;
+    if sink.redact_content {
+      redact_content(&mut request);
+      redact_content(&mut response);
+    }
+    let entry = Entry {
+      time: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |time| time.as_secs_f64()),
+      uri: DOCUMENT.try_with(|uri| uri.as_str().to_string()).ok(),
+      url: self.url,
+      request,
+      response,
+      latency_ms: self.start.elapsed().as_millis(),
+    };
+    let mut line = match serde_json::to_string(&entry) {
+      Ok(line) => line,
+      Err(error) => {
+        log::warn!("Failed to serialize trace: {}", error);
+        return;
+      }
+    };
+    if let Some(api_key) = self.api_key.filter(|api_key| !api_key.is_empty()) {
+      line = line.replace(&api_key, REDACTED);
+    }
+    if sink.lines.send(line).is_err() {
+      log::warn!("Failed to write trace: the writer has stopped");
+    }
+  }
+}
+
+/// Sends `body` as JSON, returning the response and a trace to finish once the response is read.
+pub async fn send(request: RequestBuilder, body: &impl Serialize) -> Result<(Response, Trace)> {
+  let request_body = if sink().is_some() {
+    serde_json::to_value(body)?
+  } else {
+    Value::Null
+  };
+  let (client, request) = request.json(body).build_split();
+  let request = request?;
+  let api_key = request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .map(|value| value.to_string());
+  let trace = Trace {
+    url: request.url().to_string(),
+    api_key,
+    request: request_body,
+    start: Instant::now(),
+  };
+  Ok((client.execute(request).await?, trace))
+}
+
+/// Sends `body` as JSON and parses the JSON response, tracing both.
+pub async fn post<T: DeserializeOwned>(request: RequestBuilder, body: &impl Serialize) -> Result<T> {
+  let (response, trace) = send(request, body).await?;
+  let status = response.status();
+  let text = response.text().await;
+  trace.finish(match &text {
+    Ok(text) => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone())),
+    Err(error) => json!({ "error": error.to_string() }),
+  });
+  let text = text?;
+  if !status.is_success() {
+    return Err(anyhow!("HTTP status {}: {}", status, text));
+  }
+  Ok(serde_json::from_str(&text)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    env, fs, process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use serde_json::{json, Value};
+
+  use crate::{
+    config::TraceConfig,
+    stub::{raw_server, TIMEOUT},
+  };
+
+  use super::{post, redact_content, with_sink, Sink};
+
+  #[test]
+  fn redacts_content() {
+    let mut value = json!({
+      "model": "codestral",
+      "prompt": "fn main",
+      "messages": [{ "role": "user", "content": "Fix it" }],
+      "choices": [{ "message": { "role": "assistant", "content": null } }]
+    });
+    redact_content(&mut value);
+    assert_eq!(
+      value,
+      json!({
+        "model": "codestral",
+        "prompt": "[7 chars]",
+        "messages": [{ "role": "user", "content": "[6 chars]" }],
+        "choices": [{ "message": { "role": "assistant", "content": null } }]
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn traces_failed_requests() {
+    let path = env::temp_dir().join(format!("famulus-trace-{}.jsonl", process::id()));
+    let config = TraceConfig {
+      path: Some(path.clone()),
+      redact_content: false,
+    };
+    let sink = Sink::open(&config).unwrap().map(Arc::new);
+    let (url, _requests) = raw_server(
+      "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 14\r\nConnection: close\r\n\r\nInternal error".to_string(),
+    );
+    let request = reqwest::Client::new().post(url);
+    let result = with_sink(sink, post::<Value>(request, &json!({ "prompt": "fn" }))).await;
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "HTTP status 500 Internal Server Error: Internal error"
+    );
+
+    // The trace is written by another thread.
+    let start = Instant::now();
+    let text = loop {
+      let text = fs::read_to_string(&path).unwrap_or_default();
+      if !text.is_empty() || start.elapsed() > TIMEOUT {
+        break text;
+      }
+      thread::sleep(Duration::from_millis(10));
+    };
+    fs::remove_file(&path).unwrap();
+    let entry: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(entry["request"], json!({ "prompt": "fn" }));
+    assert_eq!(entry["response"], "Internal error");
+  }
+}
@@ -0,0 +1,196 @@
+use std::{
+  collections::BTreeMap,
+  future::Future,
+  ops::AddAssign,
+  sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::UsageConfig;
+
+/// Totals per model since the server started.
+#[derive(Debug, Default)]
+pub struct Totals(Mutex<BTreeMap<String, Usage>>);
+
+// Providers record into the totals of the running task, so that the `Infill` and `Chat` traits keep returning only
+// the answers.
+tokio::task_local! {
+  static TOTALS: Arc<Totals>;
+}
+
+/// Runs `future` with its usage added to `totals`.
+pub fn with_totals<F: Future>(totals: Arc<Totals>, future: F) -> impl Future<Output = F::Output> {
+  TOTALS.scope(totals, future)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize)]
+pub struct Usage {
+  pub requests: u64,
+  pub prompt_tokens: u64,
+  pub completion_tokens: u64,
+}
+
+impl Usage {
+  pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+    Usage {
+      requests: 1,
+      prompt_tokens,
+      completion_tokens,
+    }
+  }
+}
+
+impl AddAssign for Usage {
+  fn add_assign(&mut self, other: Self) {
+    self.requests += other.requests;
+    self.prompt_tokens += other.prompt_tokens;
+    self.completion_tokens += other.completion_tokens;
+  }
+}
+
+/// The `usage` object of OpenAI compatible responses.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub struct TokenUsage {
+  #[serde(default)]
+  pub prompt_tokens: u64,
+  #[serde(default)]
+  pub completion_tokens: u64,
+}
+
+impl From<TokenUsage> for Usage {
+  fn from(usage: TokenUsage) -> Self {
+    Usage::new(usage.prompt_tokens, usage.completion_tokens)
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct ModelUsage {
+  pub model: String,
+  #[serde(flatten)]
+  pub usage: Usage,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cost: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct UsageReport {
+  pub models: Vec<ModelUsage>,
+  pub cost: f64,
+}
+
+/// The name usage is added up under: the configured model, or the one reported by the server, or else the URL.
+pub fn model_name<'a>(configured: Option<&'a str>, reported: Option<&'a str>, url: &'a str) -> &'a str {
+  configured.or(reported).unwrap_or(url)
+}
+
+pub fn record(model: &str, usage: Usage) {
+  log::info!(
+    "{}: {} prompt tokens, {} completion tokens",
+    model,
+    usage.prompt_tokens,
+    usage.completion_tokens
+  );
+  let _ = TOTALS.try_with(|totals| totals.add(model, usage));
+}
+
+impl Totals {
+  pub fn add(&self, model: &str, usage: Usage) {
+    let mut totals = self.0.lock().unwrap_or_else(|error| error.into_inner());
+    *totals.entry(model.to_string()).or_default() += usage;
+  }
+
+  pub fn report(&self, config: &UsageConfig) -> UsageReport {
+    let totals = self.0.lock().unwrap_or_else(|error| error.into_inner());
+    let models = totals
+      .iter()
+      .map(|(model, usage)| ModelUsage {
+        model: model.clone(),
+        usage: *usage,
+        cost: config.cost(model, usage),
+      })
+      .collect::<Vec<_>>();
+    let cost = models.iter().filter_map(|model| model.cost).sum();
+    UsageReport { models, cost }
+  }
+}
+
+impl UsageConfig {
+  /// Returns the cost of `usage` if a price is configured for `model`.
+  pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+    let price = self.prices.get(model)?;
+    Some((usage.prompt_tokens as f64 * price.prompt + usage.completion_tokens as f64 * price.completion) / 1e6)
+  }
+}
+
+impl UsageReport {
+  pub fn message(&self) -> String {
+    if self.models.is_empty() {
+      return "No requests yet".to_string();
+    }
+    let mut lines = self
+      .models
+      .iter()
+      .map(|model| {
+        let mut line = format!(
+          "{}: {} requests, {} prompt tokens, {} completion tokens",
+          model.model, model.usage.requests, model.usage.prompt_tokens, model.usage.completion_tokens
+        );
+        if let Some(cost) = model.cost {
+          line.push_str(&format!(", ${:.4}", cost));
+        }
+        line
+      })
+      .collect::<Vec<_>>();
+    lines.push(format!("Total cost: ${:.4}", self.cost));
+    lines.join("\n")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use crate::config::{PriceConfig, UsageConfig};
+
+  use super::{model_name, Totals, Usage};
+
+  #[test]
+  fn totals() {
+    let config = UsageConfig {
+      prices: HashMap::from([(
+        "priced".to_string(),
+        PriceConfig {
+          prompt: 1.0,
+          completion: 2.0,
+        },
+      )]),
+    };
+    let totals = Totals::default();
+    totals.add("priced", Usage::new(1_000_000, 500_000));
+    totals.add("priced", Usage::new(1_000_000, 0));
+    totals.add("free", Usage::new(10, 20));
+
+    let report = totals.report(&config);
+    let priced = report.models.iter().find(|model| model.model == "priced").unwrap();
+    assert_eq!(
+      priced.usage,
+      Usage {
+        requests: 2,
+        prompt_tokens: 2_000_000,
+        completion_tokens: 500_000,
+      }
+    );
+    assert_eq!(priced.cost, Some(3.0));
+    let free = report.models.iter().find(|model| model.model == "free").unwrap();
+    assert_eq!(free.cost, None);
+    assert_eq!(report.cost, 3.0);
+  }
+
+  #[test]
+  fn model_names() {
+    assert_eq!(model_name(Some("gpt-4o"), Some("gpt-4o-2024-08-06"), "url"), "gpt-4o");
+    assert_eq!(model_name(None, Some("qwen"), "url"), "qwen");
+    assert_eq!(model_name(None, None, "url"), "url");
+  }
+}